@@ -0,0 +1,148 @@
+use std::fmt;
+use std::collections::HashMap;
+
+pub const LABEL_TABLE_END : &str = "/// END COMPILER GENERATED LABEL TABLE ///";
+
+#[derive(Debug, Clone)]
+pub struct AsmError {
+    line : usize,
+    code : AsmErrorCode
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parsed_err = match &self.code {
+            AsmErrorCode::UnclosedComment => "Comment is never closed.".to_owned(),
+            AsmErrorCode::MissingLabelName => "Expected a name after `label`.".to_owned(),
+            AsmErrorCode::DuplicateLabel(name) => format!("Label `{}` is defined more than once.", name)
+        };
+        write!(f, "Assembler error on line {}: {}", self.line, parsed_err)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum AsmErrorCode {
+    UnclosedComment,
+    MissingLabelName,
+    DuplicateLabel(String)
+}
+
+/// Assembles `.vasm` source into the `.vraw` layout read by `tokenizer::parse_asm`.
+///
+/// Comments are stripped, every `label` is recorded at the index of its `label` token in the
+/// emitted stream and the remaining tokens are written out one source line per line.
+pub fn assemble(source : &str) -> Result<String, AsmError> {
+    let mut label_table : Vec<(&str, usize)> = Vec::new();
+    let mut seen : HashMap<&str, usize> = HashMap::new();
+    let mut lines : Vec<Vec<&str>> = Vec::new();
+    let mut count = 0;
+
+    let mut in_comment = false;
+    let mut comment_start = 0;
+    let mut expect_label = false;
+
+    for (number, line) in source.lines().enumerate() {
+        let number = number + 1;
+        let mut out : Vec<&str> = Vec::new();
+
+        for token in line.split_whitespace() {
+            if token == "//" {
+                in_comment = !in_comment;
+                comment_start = number;
+                continue;
+            }
+            if in_comment {
+                continue;
+            }
+
+            if expect_label {
+                if seen.insert(token, number).is_some() {
+                    return Err(AsmError {
+                        line: number,
+                        code: AsmErrorCode::DuplicateLabel(token.to_owned())
+                    });
+                }
+                label_table.push((token, count - 1));
+                expect_label = false;
+            } else if token == "label" {
+                expect_label = true;
+            }
+
+            out.push(token);
+            count += 1;
+        }
+
+        if !out.is_empty() {
+            lines.push(out);
+        }
+    }
+
+    if in_comment {
+        return Err(AsmError {
+            line: comment_start,
+            code: AsmErrorCode::UnclosedComment
+        });
+    }
+    if expect_label {
+        return Err(AsmError {
+            line: source.lines().count(),
+            code: AsmErrorCode::MissingLabelName
+        });
+    }
+
+    let mut vraw = String::new();
+    for (name, index) in label_table {
+        vraw.push_str(&format!("{} {}\n", name, index));
+    }
+    vraw.push_str(LABEL_TABLE_END);
+    vraw.push('\n');
+    for line in lines {
+        vraw.push_str(&line.join(" "));
+        vraw.push('\n');
+    }
+
+    Ok(vraw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(source : &str) -> (usize, AsmErrorCode) {
+        let e = assemble(source).unwrap_err();
+        (e.line, e.code)
+    }
+
+    #[test]
+    fn boot_image_matches_checked_in_vraw() {
+        assert_eq!(assemble(include_str!("../../BOOT.vasm")).unwrap(), include_str!("../../BOOT.vraw"));
+    }
+
+    #[test]
+    fn labels_point_at_their_label_token() {
+        let vraw = assemble("outstr #65\n// skipped label x //\nlabel a\n  label b // note // outstr #66\n").unwrap();
+        assert_eq!(vraw, format!("a 2\nb 4\n{}\noutstr #65\nlabel a\nlabel b outstr #66\n", LABEL_TABLE_END));
+    }
+
+    #[test]
+    fn comments_span_lines() {
+        let vraw = assemble("dmp // one\ntwo // esr\n").unwrap();
+        assert_eq!(vraw, format!("{}\ndmp\nesr\n", LABEL_TABLE_END));
+    }
+
+    #[test]
+    fn duplicate_label_is_rejected() {
+        assert!(matches!(code("label a\ndmp\nlabel a"), (3, AsmErrorCode::DuplicateLabel(name)) if name == "a"));
+    }
+
+    #[test]
+    fn missing_label_name_is_rejected() {
+        assert!(matches!(code("dmp\nlabel"), (2, AsmErrorCode::MissingLabelName)));
+        assert!(matches!(code("label // name //"), (1, AsmErrorCode::MissingLabelName)));
+    }
+
+    #[test]
+    fn unclosed_comment_is_rejected() {
+        assert!(matches!(code("dmp\n// open\nesr"), (2, AsmErrorCode::UnclosedComment)));
+    }
+}
@@ -0,0 +1,521 @@
+use std::fmt;
+use std::collections::HashMap;
+
+use crate::decoder;
+use crate::tokenizer::Assembly;
+
+pub const MAGIC : &[u8; 4] = b"VBIN";
+pub const VERSION : u8 = 1;
+
+// Ids 0-3 are the original registers, later names were appended after them to keep ids stable.
+const REGISTERS : [&str; 76] = [
+    "rax", "rbx", "rcx", "rdx",
+    "eax", "ebx", "ecx", "edx",
+    "ax", "al", "ah",
+    "bx", "bl", "bh",
+    "cx", "cl", "ch",
+    "dx", "dl", "dh",
+    "rsp", "rbp", "esp", "ebp",
+    "sp", "spl", "bp", "bpl",
+    "r0", "r0w", "r0b",
+    "r1", "r1w", "r1b",
+    "r2", "r2w", "r2b",
+    "r3", "r3w", "r3b",
+    "r4", "r4w", "r4b",
+    "r5", "r5w", "r5b",
+    "r6", "r6w", "r6b",
+    "r7", "r7w", "r7b",
+    "r8", "r8w", "r8b",
+    "r9", "r9w", "r9b",
+    "r10", "r10w", "r10b",
+    "r11", "r11w", "r11b",
+    "r12", "r12w", "r12b",
+    "r13", "r13w", "r13b",
+    "r14", "r14w", "r14b",
+    "r15", "r15w", "r15b"
+];
+
+// Operand tags, each followed by its payload.
+const TAG_REGISTER : u8 = 0x00;     // u8 register id
+const TAG_INDIRECT : u8 = 0x01;     // u8 register id, `*rax`
+const TAG_IMMEDIATE : u8 = 0x02;    // u32, `#12`
+const TAG_POINTER : u8 = 0x03;      // u32, `*12`
+const TAG_NUMBER : u8 = 0x04;       // u32, bare `12`
+const TAG_SYMBOL : u8 = 0x05;       // u16 string table index, labels and anything else
+const TAG_MEMORY : u8 = 0x06;       // u8 base id, u8 index id, u32 offset, `[rax+rbx+4]`
+
+// Register id standing in for a missing base or index in `TAG_MEMORY`.
+const NO_REGISTER : u8 = 0xFF;
+
+// Opcode number is the position in this table, paired with the operand count.
+const OPCODES : [(&str, usize); 73] = [
+    ("label", 1),
+    ("dmp", 0),
+    ("panic", 0),
+    ("fault", 0),
+    ("memset", 2),
+    ("mov", 2),
+    ("add", 3),
+    ("sub", 3),
+    ("mul", 3),
+    ("div", 3),
+    ("goto", 1),
+    ("cgt", 2),
+    ("grt", 3),
+    ("lt", 3),
+    ("eq", 3),
+    ("outstr", 1),
+    ("outbyte", 1),
+    ("vfsr", 2),
+    ("vrlx", 1),
+    ("inv", 1),
+    ("push", 1),
+    ("pop", 1),
+    ("call", 1),
+    ("esr", 0),
+    ("rdf", 1),
+    ("pushf", 0),
+    ("popf", 0),
+    ("cmp", 2),
+    ("jz", 1),
+    ("jnz", 1),
+    ("je", 1),
+    ("jne", 1),
+    ("jl", 1),
+    ("jle", 1),
+    ("jg", 1),
+    ("jge", 1),
+    ("jb", 1),
+    ("jbe", 1),
+    ("ja", 1),
+    ("jae", 1),
+    ("callz", 1),
+    ("callnz", 1),
+    ("calle", 1),
+    ("callne", 1),
+    ("calll", 1),
+    ("callle", 1),
+    ("callg", 1),
+    ("callge", 1),
+    ("callb", 1),
+    ("callbe", 1),
+    ("calla", 1),
+    ("callae", 1),
+    ("and", 3),
+    ("or", 3),
+    ("xor", 3),
+    ("not", 2),
+    ("shl", 3),
+    ("shr", 3),
+    ("sar", 3),
+    ("rol", 3),
+    ("ror", 3),
+    ("load16", 2),
+    ("load32", 2),
+    ("store16", 2),
+    ("store32", 2),
+    // Always encoded with its code, a bare `hlt` gets `#0`.
+    ("hlt", 1),
+    ("int", 1),
+    ("iret", 0),
+    ("ivt", 2),
+    ("sys", 1),
+    ("inbyte", 1),
+    ("poll", 1),
+    ("readln", 3)
+];
+
+#[derive(Debug, Clone)]
+pub struct BytecodeError {
+    code : BytecodeErrorCode
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parsed_err = match &self.code {
+            BytecodeErrorCode::BadMagic => "Missing VBIN header.".to_owned(),
+            BytecodeErrorCode::BadVersion(v) => format!("Unsupported format version {}.", v),
+            BytecodeErrorCode::Truncated => "Unexpected end of image.".to_owned(),
+            BytecodeErrorCode::UnknownOpcode(op) => format!("Unknown opcode `{}`.", op),
+            BytecodeErrorCode::MissingOperand(op) => format!("Missing operand after `{}`.", op),
+            BytecodeErrorCode::BadOperandTag(tag) => format!("Unknown operand tag {:#04x}.", tag),
+            BytecodeErrorCode::BadRegister(id) => format!("Unknown register id {}.", id),
+            BytecodeErrorCode::BadIndex(index) => format!("String table index {} out of range.", index),
+            BytecodeErrorCode::TooLarge(token) => format!("`{}` does not fit in the image format.", token),
+            BytecodeErrorCode::BadLabel(name) => format!("Label `{}` does not point at an instruction.", name)
+        };
+        write!(f, "Bytecode error: {}", parsed_err)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum BytecodeErrorCode {
+    BadMagic,
+    BadVersion(u8),
+    Truncated,
+    UnknownOpcode(String),
+    MissingOperand(String),
+    BadOperandTag(u8),
+    BadRegister(u8),
+    BadIndex(u16),
+    TooLarge(String),
+    BadLabel(String)
+}
+
+fn error(code : BytecodeErrorCode) -> BytecodeError {
+    BytecodeError { code }
+}
+
+pub fn is_bytecode(bytes : &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Encodes a parsed program into a `.vbin` image.
+///
+/// Layout, all integers little-endian:
+///
+/// ```text
+/// "VBIN" version:u8
+/// strings:u16  { len:u8 bytes }*
+/// labels:u16   { string:u16 token:u32 }*
+/// { opcode:u8 { tag:u8 payload }* }*
+/// ```
+///
+/// Comments left in the token stream are dropped and label indices adjusted to match.
+pub fn encode<'a>(assembly : &'a Assembly) -> Result<Vec<u8>, BytecodeError> {
+    let mut strings : Vec<&'a str> = Vec::new();
+    let mut string_ids : HashMap<&'a str, u16> = HashMap::new();
+    let mut intern = |s : &'a str| -> Result<u16, BytecodeError> {
+        if let Some(id) = string_ids.get(s) {
+            return Ok(*id);
+        }
+        if s.len() > u8::MAX as usize || strings.len() >= u16::MAX as usize {
+            return Err(error(BytecodeErrorCode::TooLarge(s.to_owned())));
+        }
+        let id = strings.len() as u16;
+        strings.push(s);
+        string_ids.insert(s, id);
+        Ok(id)
+    };
+
+    let mut body : Vec<u8> = Vec::new();
+    // Old token index of each instruction -> token index in the encoded stream.
+    let mut remap : HashMap<usize, usize> = HashMap::new();
+    let mut emitted = 0;
+
+    let tokens = &assembly.tokens;
+    let mut index = 0;
+    while index < tokens.len() {
+        let token = tokens[index];
+
+        if token == "//" {
+            index += 1;
+            while index < tokens.len() && tokens[index] != "//" {
+                index += 1;
+            }
+            index += 1;
+            continue;
+        }
+
+        let opcode = OPCODES.iter()
+            .position(|(name, _)| *name == token)
+            .ok_or_else(|| error(BytecodeErrorCode::UnknownOpcode(token.to_owned())))?;
+        let arity = OPCODES[opcode].1;
+
+        remap.insert(index, emitted);
+        body.push(opcode as u8);
+        index += 1;
+        emitted += 1;
+
+        if token == "hlt" && !tokens.get(index).is_some_and(|t| decoder::is_value(t)) {
+            body.push(TAG_IMMEDIATE);
+            body.extend_from_slice(&0u32.to_le_bytes());
+            emitted += 1;
+            continue;
+        }
+
+        for _ in 0..arity {
+            let operand = *tokens.get(index)
+                .ok_or_else(|| error(BytecodeErrorCode::MissingOperand(token.to_owned())))?;
+            encode_operand(operand, &mut body, &mut intern)?;
+            index += 1;
+            emitted += 1;
+        }
+    }
+    remap.insert(index, emitted);
+
+    let mut labels : Vec<(&String, &usize)> = assembly.label_table.iter().collect();
+    labels.sort_by_key(|(_, index)| **index);
+
+    let mut label_bytes : Vec<u8> = Vec::new();
+    for (name, index) in &labels {
+        let name = name.as_str();
+        let target = *remap.get(index)
+            .ok_or_else(|| error(BytecodeErrorCode::BadLabel(name.to_owned())))?;
+        label_bytes.extend_from_slice(&intern(name)?.to_le_bytes());
+        label_bytes.extend_from_slice(&to_u32(target, name)?.to_le_bytes());
+    }
+
+    let mut image : Vec<u8> = Vec::new();
+    image.extend_from_slice(MAGIC);
+    image.push(VERSION);
+    image.extend_from_slice(&(strings.len() as u16).to_le_bytes());
+    for s in &strings {
+        image.push(s.len() as u8);
+        image.extend_from_slice(s.as_bytes());
+    }
+    image.extend_from_slice(&(labels.len() as u16).to_le_bytes());
+    image.extend_from_slice(&label_bytes);
+    image.extend_from_slice(&body);
+
+    Ok(image)
+}
+
+fn encode_operand<'a>(
+    token : &'a str,
+    out : &mut Vec<u8>,
+    intern : &mut impl FnMut(&'a str) -> Result<u16, BytecodeError>
+) -> Result<(), BytecodeError> {
+    if let Some(reg) = REGISTERS.iter().position(|r| *r == token) {
+        out.push(TAG_REGISTER);
+        out.push(reg as u8);
+        return Ok(());
+    }
+
+    if let Some(rest) = token.strip_prefix('*') {
+        if let Some(reg) = REGISTERS.iter().position(|r| *r == rest) {
+            out.push(TAG_INDIRECT);
+            out.push(reg as u8);
+            return Ok(());
+        }
+        if let Ok(value) = rest.parse::<u32>() {
+            out.push(TAG_POINTER);
+            out.extend_from_slice(&value.to_le_bytes());
+            return Ok(());
+        }
+    }
+
+    if let Some(rest) = token.strip_prefix('#') {
+        if let Ok(value) = rest.parse::<u32>() {
+            out.push(TAG_IMMEDIATE);
+            out.extend_from_slice(&value.to_le_bytes());
+            return Ok(());
+        }
+    }
+
+    if let Some((base, index, offset)) = memory_operand(token) {
+        out.push(TAG_MEMORY);
+        out.push(base);
+        out.push(index);
+        out.extend_from_slice(&offset.to_le_bytes());
+        return Ok(());
+    }
+
+    if let Ok(value) = token.parse::<u32>() {
+        out.push(TAG_NUMBER);
+        out.extend_from_slice(&value.to_le_bytes());
+        return Ok(());
+    }
+
+    out.push(TAG_SYMBOL);
+    out.extend_from_slice(&intern(token)?.to_le_bytes());
+    Ok(())
+}
+
+// Splits `[rax+rbx+4]` and its shorter forms the way the decoder reads them, up to two registers
+// and one offset. Anything else is left to be stored as a symbol.
+fn memory_operand(token : &str) -> Option<(u8, u8, u32)> {
+    let inner = token.strip_prefix('[')?.strip_suffix(']')?;
+    let mut registers : Vec<u8> = Vec::new();
+    let mut offset = None;
+    for part in inner.split('+') {
+        match (REGISTERS.iter().position(|r| *r == part), part.parse::<u32>()) {
+            (Some(reg), _) if registers.len() < 2 => registers.push(reg as u8),
+            (None, Ok(value)) if offset.is_none() => offset = Some(value),
+            _ => return None
+        }
+    }
+    let base = registers.first().copied().unwrap_or(NO_REGISTER);
+    let index = registers.get(1).copied().unwrap_or(NO_REGISTER);
+    Some((base, index, offset.unwrap_or(0)))
+}
+
+fn to_u32(value : usize, context : &str) -> Result<u32, BytecodeError> {
+    u32::try_from(value).map_err(|_| error(BytecodeErrorCode::TooLarge(context.to_owned())))
+}
+
+struct Reader<'a> {
+    bytes : &'a [u8],
+    pos : usize
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n : usize) -> Result<&'a [u8], BytecodeError> {
+        if self.pos + n > self.bytes.len() {
+            return Err(error(BytecodeErrorCode::Truncated));
+        }
+        let slice = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+    fn u8(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> Result<u16, BytecodeError> {
+        Ok(u16::from_le(bytemuck::pod_read_unaligned(self.take(2)?)))
+    }
+    fn u32(&mut self) -> Result<u32, BytecodeError> {
+        Ok(u32::from_le(bytemuck::pod_read_unaligned(self.take(4)?)))
+    }
+    fn done(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+}
+
+/// Loads a `.vbin` image back into the token form the executor runs.
+pub fn load(bytes : &[u8]) -> Result<Assembly, BytecodeError> {
+    let mut reader = Reader { bytes, pos: 0 };
+
+    if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(error(BytecodeErrorCode::BadMagic));
+    }
+    let version = reader.u8()?;
+    if version != VERSION {
+        return Err(error(BytecodeErrorCode::BadVersion(version)));
+    }
+
+    let string_count = reader.u16()?;
+    let mut strings : Vec<&'static str> = Vec::with_capacity(string_count as usize);
+    for _ in 0..string_count {
+        let len = reader.u8()? as usize;
+        let raw = String::from_utf8_lossy(reader.take(len)?).to_string();
+        strings.push(Box::leak(raw.into_boxed_str()));
+    }
+    let string = |index : u16| -> Result<&'static str, BytecodeError> {
+        strings.get(index as usize).copied().ok_or_else(|| error(BytecodeErrorCode::BadIndex(index)))
+    };
+
+    let label_count = reader.u16()?;
+    let mut label_table : HashMap<String, usize> = HashMap::new();
+    for _ in 0..label_count {
+        let name = string(reader.u16()?)?;
+        let index = reader.u32()? as usize;
+        label_table.insert(name.to_owned(), index);
+    }
+
+    let mut tokens : Vec<&'static str> = Vec::new();
+    while !reader.done() {
+        let opcode = reader.u8()?;
+        let (name, arity) = *OPCODES.get(opcode as usize)
+            .ok_or_else(|| error(BytecodeErrorCode::UnknownOpcode(opcode.to_string())))?;
+        tokens.push(name);
+
+        for _ in 0..arity {
+            let token : &'static str = match reader.u8()? {
+                TAG_REGISTER => register(reader.u8()?)?,
+                TAG_INDIRECT => leak(format!("*{}", register(reader.u8()?)?)),
+                TAG_IMMEDIATE => leak(format!("#{}", reader.u32()?)),
+                TAG_POINTER => leak(format!("*{}", reader.u32()?)),
+                TAG_NUMBER => leak(reader.u32()?.to_string()),
+                TAG_SYMBOL => string(reader.u16()?)?,
+                TAG_MEMORY => {
+                    let mut parts : Vec<String> = Vec::new();
+                    for id in [reader.u8()?, reader.u8()?] {
+                        if id != NO_REGISTER {
+                            parts.push(register(id)?.to_owned());
+                        }
+                    }
+                    let offset = reader.u32()?;
+                    if offset != 0 || parts.is_empty() {
+                        parts.push(offset.to_string());
+                    }
+                    leak(format!("[{}]", parts.join("+")))
+                },
+                tag => return Err(error(BytecodeErrorCode::BadOperandTag(tag)))
+            };
+            tokens.push(token);
+        }
+    }
+
+    Ok(Assembly {
+        label_table,
+        tokens
+    })
+}
+
+fn register(id : u8) -> Result<&'static str, BytecodeError> {
+    REGISTERS.get(id as usize)
+        .copied()
+        .ok_or_else(|| error(BytecodeErrorCode::BadRegister(id)))
+}
+
+fn leak(s : String) -> &'static str {
+    Box::leak(s.into_boxed_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::parse_asm;
+
+    fn round_trip(image : &'static str) -> Assembly {
+        load(&encode(&parse_asm(image).unwrap()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn round_trip_keeps_tokens_and_labels() {
+        let loaded = round_trip(
+            "main 6 end 32 fin 38\n/// END COMPILER GENERATED LABEL TABLE ///\n\
+             // boot // call main hlt\n\
+             label main mov #7 rax mov *rax *12 memset 12 65 goto end\n\
+             mov [rax] [12] mov [ebx+4] [rcx+r12b] mov [4+rax+al] [0] add [rax+0] 1 [sp+4294967295]\n\
+             label end // tail // esr"
+        );
+
+        assert_eq!(loaded.tokens, [
+            "call", "main", "hlt", "#0",
+            "label", "main", "mov", "#7", "rax", "mov", "*rax", "*12", "memset", "12", "65", "goto", "end",
+            "mov", "[rax]", "[12]", "mov", "[ebx+4]", "[rcx+r12b]", "mov", "[rax+al+4]", "[0]",
+            "add", "[rax]", "1", "[sp+4294967295]",
+            "label", "end", "esr"
+        ]);
+        assert_eq!(loaded.label_table["main"], 4);
+        assert_eq!(loaded.label_table["end"], 30);
+        assert_eq!(loaded.label_table["fin"], 33);
+    }
+
+    #[test]
+    fn hlt_keeps_its_code() {
+        assert_eq!(round_trip("/// END COMPILER GENERATED LABEL TABLE ///\nhlt #3").tokens, ["hlt", "#3"]);
+        assert_eq!(round_trip("/// END COMPILER GENERATED LABEL TABLE ///\nhlt rbx").tokens, ["hlt", "rbx"]);
+    }
+
+    #[test]
+    fn memory_operands_are_encoded_numerically() {
+        let image = encode(&parse_asm("/// END COMPILER GENERATED LABEL TABLE ///\nmov [rbx+rcx+8] [16]").unwrap()).unwrap();
+        // No strings or labels, just `mov` and two memory operands.
+        assert_eq!(&image[5..9], &[0, 0, 0, 0]);
+        assert_eq!(image[9], 5);
+        assert_eq!(&image[10..17], &[TAG_MEMORY, 1, 2, 8, 0, 0, 0]);
+        assert_eq!(&image[17..24], &[TAG_MEMORY, NO_REGISTER, NO_REGISTER, 16, 0, 0, 0]);
+
+        // Malformed forms are kept as text for the decoder to reject.
+        let loaded = round_trip("/// END COMPILER GENERATED LABEL TABLE ///\nmov [rax+rbx+rcx] [1+2]");
+        assert_eq!(loaded.tokens, ["mov", "[rax+rbx+rcx]", "[1+2]"]);
+    }
+
+    #[test]
+    fn label_into_operand_is_rejected() {
+        let assembly = parse_asm("main 1\n/// END COMPILER GENERATED LABEL TABLE ///\ncall main").unwrap();
+        assert!(encode(&assembly).is_err());
+    }
+
+    #[test]
+    fn bad_images_are_rejected() {
+        assert!(load(b"VBIM\x01").is_err());
+        assert!(load(b"VBIN\x02").is_err());
+        assert!(load(b"VBIN\x01\x00").is_err());
+        assert!(load(b"VBIN\x01\x00\x00\x00\x00\xff").is_err());
+        assert!(load(b"VBIN\x01\x00\x00\x00\x00\x00\x09").is_err());
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+/// Where `inbyte`, `poll` and `readln` read from. Sources are `Send` so the executor owning them
+/// can be moved to another thread.
+pub trait InputSource : Send {
+    /// Next byte, waiting for one if necessary. `None` at the end of input.
+    fn read_byte(&mut self) -> io::Result<Option<u8>>;
+    /// Whether `read_byte` would return without waiting, which includes the end of input.
+    fn ready(&mut self) -> io::Result<bool>;
+}
+
+/// Reads the host's stdin.
+///
+/// Stdin is read by a background thread, started on first use, so `ready` can answer without
+/// blocking. The thread reads one byte at a time and only when asked, so stdin is locked just
+/// while a read is outstanding: during `read_byte`, and from a `ready` that found nothing until
+/// the next byte arrives. Host code reading stdin in that window waits for the guest's byte to
+/// be taken first. Dropping the source stops the thread once that read completes, and a byte
+/// read for it but never taken is lost.
+#[derive(Default)]
+pub struct StdinInput {
+    reader : Option<StdinReader>,
+    // A byte has been asked for and not yet received.
+    pending : bool,
+    // Byte received by `ready` and not yet read.
+    peeked : Option<Option<u8>>
+}
+
+struct StdinReader {
+    requests : Sender<()>,
+    replies : Receiver<io::Result<Option<u8>>>
+}
+
+impl StdinInput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Asks the thread for the next byte unless it is already reading one.
+    fn request(&mut self) -> &Receiver<io::Result<Option<u8>>> {
+        let reader = self.reader.get_or_insert_with(|| {
+            let (requests, wanted) = mpsc::channel::<()>();
+            let (tx, replies) = mpsc::channel();
+            thread::spawn(move || {
+                // Ends when the source, and with it the sender, is dropped.
+                for () in wanted {
+                    if tx.send(read_stdin_byte()).is_err() {
+                        break;
+                    }
+                }
+            });
+            StdinReader { requests, replies }
+        });
+        if !self.pending {
+            // The thread only exits once the receiver is gone, so this can't fail.
+            let _ = reader.requests.send(());
+            self.pending = true;
+        }
+        &reader.replies
+    }
+}
+
+// Locks stdin for this one read only.
+fn read_stdin_byte() -> io::Result<Option<u8>> {
+    let mut byte = [0u8];
+    loop {
+        match io::stdin().lock().read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+impl InputSource for StdinInput {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.peeked.take() {
+            return Ok(byte);
+        }
+        let reply = self.request().recv();
+        self.pending = false;
+        reply.unwrap_or(Ok(None))
+    }
+
+    fn ready(&mut self) -> io::Result<bool> {
+        if self.peeked.is_some() {
+            return Ok(true);
+        }
+        match self.request().try_recv() {
+            Ok(byte) => {
+                self.pending = false;
+                self.peeked = Some(byte?);
+                Ok(true)
+            },
+            Err(TryRecvError::Empty) => Ok(false),
+            Err(TryRecvError::Disconnected) => {
+                self.pending = false;
+                self.peeked = Some(None);
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// Serves input from a fixed buffer, for scripted runs and tests.
+pub struct BufferInput {
+    bytes : VecDeque<u8>
+}
+
+impl BufferInput {
+    pub fn new(bytes : impl Into<Vec<u8>>) -> Self {
+        Self { bytes: bytes.into().into() }
+    }
+}
+
+impl InputSource for BufferInput {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.bytes.pop_front())
+    }
+
+    fn ready(&mut self) -> io::Result<bool> {
+        Ok(true)
+    }
+}
+
+/// Where `outstr`, `outbyte` and `dmp` write to. Like input sources, sinks are `Send`.
+pub trait OutputSink : Send {
+    fn write(&mut self, bytes : &[u8]) -> io::Result<()>;
+    /// Called when the machine stops and whenever the host needs output to be visible.
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Writes to the host's stdout.
+#[derive(Default)]
+pub struct StdoutOutput;
+
+impl OutputSink for StdoutOutput {
+    fn write(&mut self, bytes : &[u8]) -> io::Result<()> {
+        io::stdout().write_all(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+/// Collects output in memory. Clones share the same buffer, so keep one to read what the guest
+/// wrote after handing another to the `Executor`.
+#[derive(Clone, Default)]
+pub struct BufferOutput {
+    bytes : Arc<Mutex<Vec<u8>>>
+}
+
+impl BufferOutput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn contents(&self) -> Vec<u8> {
+        self.buffer().clone()
+    }
+    /// Contents as text, with invalid UTF-8 replaced.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.buffer()).into_owned()
+    }
+
+    // A panic while holding the lock can't leave the bytes half-written, so poisoning is ignored.
+    fn buffer(&self) -> MutexGuard<'_, Vec<u8>> {
+        self.bytes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl OutputSink for BufferOutput {
+    fn write(&mut self, bytes : &[u8]) -> io::Result<()> {
+        self.buffer().extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes to a host file, buffered until flushed.
+pub struct FileOutput {
+    out : BufWriter<File>
+}
+
+impl FileOutput {
+    pub fn create(path : impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self { out: BufWriter::new(File::create(path)?) })
+    }
+}
+
+impl OutputSink for FileOutput {
+    fn write(&mut self, bytes : &[u8]) -> io::Result<()> {
+        self.out.write_all(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Sends output to two sinks, e.g. stdout and a buffer to compare against.
+pub struct TeeOutput {
+    first : Box<dyn OutputSink>,
+    second : Box<dyn OutputSink>
+}
+
+impl TeeOutput {
+    pub fn new(first : Box<dyn OutputSink>, second : Box<dyn OutputSink>) -> Self {
+        Self { first, second }
+    }
+}
+
+impl OutputSink for TeeOutput {
+    fn write(&mut self, bytes : &[u8]) -> io::Result<()> {
+        self.first.write(bytes)?;
+        self.second.write(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.first.flush()?;
+        self.second.flush()
+    }
+}
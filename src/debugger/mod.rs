@@ -0,0 +1,271 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+use crate::decoder::Instruction;
+use crate::exec::{format_flags, ExecError, Executor, ExitReason};
+
+const HELP : &str = "Commands:
+  s, step               execute one instruction
+  n, next               step, running a `call` through to its return
+  c, continue           run until a breakpoint or the end of the program
+  f, finish             run until the current subroutine returns (`esr`)
+  b, break <label|n>    break on a label or instruction index
+  d, delete <label|n>   remove a breakpoint
+  bl, breakpoints       list breakpoints
+  r, regs               print registers and flags
+  x, mem <addr> [len]   print memory
+  bt, stack             print the call stack and data stack
+  w, where              print the current instruction
+  q, quit               leave the debugger";
+
+enum Stop {
+    NotRunning,
+    Stepped,
+    Breakpoint,
+    Exited(ExitReason),
+    Errored(ExecError)
+}
+
+pub struct Debugger {
+    exec : Executor,
+    breakpoints : BTreeSet<usize>,
+    finished : bool
+}
+
+impl Debugger {
+    pub fn new(exec : Executor) -> Self {
+        Self {
+            exec,
+            breakpoints: BTreeSet::new(),
+            finished: false
+        }
+    }
+
+    pub fn executor(&self) -> &Executor {
+        &self.exec
+    }
+
+    /// Reads commands from `input` until it is exhausted or `quit` is entered.
+    pub fn repl(&mut self, input : impl BufRead, out : &mut impl Write) -> io::Result<()> {
+        self.print_location(out)?;
+        write!(out, "(vdb) ")?;
+        out.flush()?;
+
+        for line in input.lines() {
+            if !self.command(&line?, out)? {
+                break;
+            }
+            write!(out, "(vdb) ")?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Runs one debugger command, returning false once the user asks to quit.
+    pub fn command(&mut self, line : &str, out : &mut impl Write) -> io::Result<bool> {
+        let words : Vec<&str> = line.split_whitespace().collect();
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Ok(true)
+        };
+
+        match command {
+            "s" | "step" => {
+                let stop = self.resume(|_| true);
+                self.report(stop, out)?;
+            },
+            "n" | "next" => {
+                let depth = self.exec.call_depth();
+                let stop = if self.at_call() {
+                    self.resume(|d| d.exec.call_depth() <= depth)
+                } else {
+                    self.resume(|_| true)
+                };
+                self.report(stop, out)?;
+            },
+            "c" | "continue" => {
+                let stop = self.resume(|_| false);
+                self.report(stop, out)?;
+            },
+            "f" | "finish" => {
+                let depth = self.exec.call_depth();
+                if depth == 0 {
+                    writeln!(out, "Not inside a subroutine.")?;
+                } else {
+                    let stop = self.resume(|d| d.exec.call_depth() < depth);
+                    self.report(stop, out)?;
+                }
+            },
+            "b" | "break" => match args.first().and_then(|a| self.resolve(a)) {
+                Some(index) => {
+                    self.breakpoints.insert(index);
+                    writeln!(out, "Breakpoint at {}", self.describe(index))?;
+                },
+                None => writeln!(out, "Expected a label or instruction index.")?
+            },
+            "d" | "delete" => match args.first().and_then(|a| self.resolve(a)) {
+                Some(index) if self.breakpoints.remove(&index) => writeln!(out, "Deleted breakpoint at #{}", index)?,
+                _ => writeln!(out, "No such breakpoint.")?
+            },
+            "bl" | "breakpoints" => {
+                if self.breakpoints.is_empty() {
+                    writeln!(out, "No breakpoints.")?;
+                }
+                for index in &self.breakpoints {
+                    writeln!(out, "  {}", self.describe(*index))?;
+                }
+            },
+            "r" | "regs" => {
+                for reg in self.exec.register_file() {
+                    writeln!(out, "  {}: {}", reg.name().to_uppercase(), self.exec.register(reg))?;
+                }
+                writeln!(out, "  FLAGS: {}", format_flags(self.exec.flags()))?;
+            },
+            "x" | "mem" => {
+                let start = args.first().and_then(|a| a.parse::<usize>().ok());
+                let len = args.get(1).and_then(|a| a.parse::<usize>().ok()).unwrap_or(16);
+                match start {
+                    Some(start) => self.print_memory(start, len, out)?,
+                    None => writeln!(out, "Expected an address.")?
+                }
+            },
+            "bt" | "stack" => {
+                writeln!(out, "Call stack:")?;
+                writeln!(out, "  #0 {}", self.describe(self.exec.index()))?;
+                // Return indices point just past each call site.
+                for (depth, ret) in self.exec.call_stack().iter().rev().enumerate() {
+                    writeln!(out, "  #{} {}", depth + 1, self.describe(ret.saturating_sub(1)))?;
+                }
+                writeln!(out, "Data stack: {:?}", self.exec.stack_entries())?;
+            },
+            "w" | "where" => self.print_location(out)?,
+            "h" | "help" => writeln!(out, "{}", HELP)?,
+            "q" | "quit" => return Ok(false),
+            _ => writeln!(out, "Unknown command `{}`, try `help`.", command)?
+        }
+
+        Ok(true)
+    }
+
+    // Steps until `done` holds after a step, a breakpoint is reached or the program stops.
+    fn resume(&mut self, done : impl Fn(&Self) -> bool) -> Stop {
+        if self.finished {
+            return Stop::NotRunning;
+        }
+
+        loop {
+            match self.exec.step() {
+                Err(e) => {
+                    self.finished = true;
+                    return Stop::Errored(e);
+                },
+                Ok(Some(reason)) => {
+                    self.finished = true;
+                    return Stop::Exited(reason);
+                },
+                Ok(None) => {}
+            }
+
+            let stop = if done(self) {
+                Stop::Stepped
+            } else if self.breakpoints.contains(&self.exec.index()) {
+                Stop::Breakpoint
+            } else {
+                continue;
+            };
+            // Show guest output before the debugger's own. The machine flushes it when it stops.
+            return match self.exec.flush_output() {
+                Ok(()) => stop,
+                Err(e) => {
+                    self.finished = true;
+                    Stop::Errored(e)
+                }
+            };
+        }
+    }
+
+    fn at_call(&self) -> bool {
+        matches!(self.exec.current_instruction(), Some(Instruction::Call(_) | Instruction::CallIf { .. }))
+    }
+
+    fn report(&self, stop : Stop, out : &mut impl Write) -> io::Result<()> {
+        match stop {
+            Stop::NotRunning => writeln!(out, "The program is not running."),
+            Stop::Stepped => self.print_location(out),
+            Stop::Breakpoint => {
+                write!(out, "Breakpoint, ")?;
+                self.print_location(out)
+            },
+            Stop::Exited(reason) => writeln!(out, "Program exited: {:?} (status {})", reason, reason.code()),
+            Stop::Errored(e) => writeln!(out, "{}", e)
+        }
+    }
+
+    fn print_location(&self, out : &mut impl Write) -> io::Result<()> {
+        writeln!(out, "{}", self.describe(self.exec.index()))
+    }
+
+    // `#12 main+3: add rax rbx rcx`
+    fn describe(&self, index : usize) -> String {
+        let program = self.exec.program();
+        let label = match program.label_at(index) {
+            Some((name, 0)) => format!(" {}", name),
+            Some((name, offset)) => format!(" {}+{}", name, offset),
+            None => String::new()
+        };
+        let instruction = match program.instructions.get(index) {
+            Some(instruction) => instruction.to_string(),
+            None => "<end of program>".to_owned()
+        };
+        format!("#{}{}: {}", index, label, instruction)
+    }
+
+    // Label name or instruction index.
+    fn resolve(&self, target : &str) -> Option<usize> {
+        if let Some(index) = self.exec.program().labels.get(target) {
+            return Some(*index);
+        }
+        target.parse::<usize>().ok()
+    }
+
+    fn print_memory(&self, start : usize, len : usize, out : &mut impl Write) -> io::Result<()> {
+        let memory = self.exec.memory();
+        if start >= memory.len() {
+            return writeln!(out, "Address {} is outside of memory ({} bytes).", start, memory.len());
+        }
+
+        let end = start.saturating_add(len).min(memory.len());
+        for row in (start..end).step_by(8) {
+            let bytes = memory[row..(row + 8).min(end)].iter()
+                .map(|b| b.to_string())
+                .collect::<Vec<String>>()
+                .join(" ");
+            writeln!(out, "  {}: {}", row, bytes)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::MachineConfig;
+    use crate::tokenizer::parse_asm;
+    use crate::vfs::VFS;
+
+    fn session(commands : &str) -> String {
+        let assembly = parse_asm("/// END COMPILER GENERATED LABEL TABLE ///\nmemset 9 7").unwrap();
+        let config = MachineConfig { mem_size: 16, ..MachineConfig::default() };
+        let mut debugger = Debugger::new(Executor::new(assembly, VFS::create_empty(), config).unwrap());
+        let mut out : Vec<u8> = Vec::new();
+        debugger.repl(commands.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn memory_length_is_clamped() {
+        let out = session("s\nx 8 18446744073709551615\nx 16\n");
+        assert!(out.contains("  8: 0 7 0 0 0 0 0 0\n"), "{}", out);
+        assert!(out.contains("Address 16 is outside of memory (16 bytes)."), "{}", out);
+    }
+}
@@ -0,0 +1,816 @@
+use std::fmt;
+use std::collections::HashMap;
+
+use crate::tokenizer::Assembly;
+
+/// Part of a register an operand names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterView {
+    /// All 32 bits, `rax` or `eax`.
+    Full,
+    /// Low 16 bits, `ax`.
+    Word,
+    /// Low 8 bits, `al`.
+    Low,
+    /// Bits 8 to 15, `ah`.
+    High
+}
+
+/// Number of general purpose registers, `r0` to `r15`.
+pub const GENERAL_REGISTERS : usize = 16;
+
+// Names of each register's views, in RegisterView order. The first four general registers keep
+// their legacy names, which are also how they are displayed.
+const LEGACY_NAMES : [[&str; 4]; 4] = [
+    ["rax", "ax", "al", "ah"],
+    ["rbx", "bx", "bl", "bh"],
+    ["rcx", "cx", "cl", "ch"],
+    ["rdx", "dx", "dl", "dh"]
+];
+// `r0`..`r15` have no high byte view.
+const GENERAL_NAMES : [[&str; 3]; GENERAL_REGISTERS] = [
+    ["r0", "r0w", "r0b"],
+    ["r1", "r1w", "r1b"],
+    ["r2", "r2w", "r2b"],
+    ["r3", "r3w", "r3b"],
+    ["r4", "r4w", "r4b"],
+    ["r5", "r5w", "r5b"],
+    ["r6", "r6w", "r6b"],
+    ["r7", "r7w", "r7b"],
+    ["r8", "r8w", "r8b"],
+    ["r9", "r9w", "r9b"],
+    ["r10", "r10w", "r10b"],
+    ["r11", "r11w", "r11b"],
+    ["r12", "r12w", "r12b"],
+    ["r13", "r13w", "r13b"],
+    ["r14", "r14w", "r14b"],
+    ["r15", "r15w", "r15b"]
+];
+// Stack and frame pointers, in the slots after the general registers.
+const POINTER_NAMES : [[&str; 3]; 2] = [
+    ["rsp", "sp", "spl"],
+    ["rbp", "bp", "bpl"]
+];
+const FULL_ALIASES : [(&str, u8); 6] = [("eax", 0), ("ebx", 1), ("ecx", 2), ("edx", 3), ("esp", 16), ("ebp", 17)];
+const VIEWS : [RegisterView; 4] = [RegisterView::Full, RegisterView::Word, RegisterView::Low, RegisterView::High];
+
+/// A 32-bit register, or a 16 or 8 bit view of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Register {
+    slot : u8,
+    view : RegisterView
+}
+
+impl Register {
+    pub const RAX : Register = Register::full(0);
+    pub const RBX : Register = Register::full(1);
+    pub const RCX : Register = Register::full(2);
+    pub const RDX : Register = Register::full(3);
+    /// Stack pointer, used by `push` and `pop` when the stack lives in memory.
+    pub const RSP : Register = Register::full(GENERAL_REGISTERS as u8);
+    /// Frame pointer.
+    pub const RBP : Register = Register::full(GENERAL_REGISTERS as u8 + 1);
+    /// The general registers in order, then `rsp` and `rbp`.
+    pub const ALL : [Register; GENERAL_REGISTERS + 2] = {
+        let mut all = [Register::RAX; GENERAL_REGISTERS + 2];
+        let mut slot = 0;
+        while slot < all.len() {
+            all[slot] = Register::full(slot as u8);
+            slot += 1;
+        }
+        all
+    };
+
+    const fn full(slot : u8) -> Self {
+        Register { slot, view: RegisterView::Full }
+    }
+
+    pub fn parse(token : &str) -> Option<Self> {
+        if let Some((_, slot)) = FULL_ALIASES.iter().find(|(name, _)| *name == token) {
+            return Some(Register::full(*slot));
+        }
+
+        let find = |names : &[&str], slot : usize| {
+            names.iter()
+                .position(|name| *name == token)
+                .map(|view| Register { slot: slot as u8, view: VIEWS[view] })
+        };
+        LEGACY_NAMES.iter().enumerate().find_map(|(slot, names)| find(names, slot))
+            .or_else(|| GENERAL_NAMES.iter().enumerate().find_map(|(slot, names)| find(names, slot)))
+            .or_else(|| POINTER_NAMES.iter().enumerate().find_map(|(n, names)| find(names, GENERAL_REGISTERS + n)))
+    }
+    /// Whether this is one of `r0`..`r15` rather than `rsp` or `rbp`.
+    pub fn is_general(self) -> bool {
+        self.index() < GENERAL_REGISTERS
+    }
+    /// Index of the underlying 32-bit register.
+    pub fn index(self) -> usize {
+        self.slot as usize
+    }
+    pub fn view(self) -> RegisterView {
+        self.view
+    }
+    pub fn name(self) -> &'static str {
+        let view = VIEWS.iter().position(|v| *v == self.view).unwrap_or(0);
+        match self.index() {
+            slot if slot < LEGACY_NAMES.len() => LEGACY_NAMES[slot][view],
+            slot if slot < GENERAL_REGISTERS => GENERAL_NAMES[slot].get(view).copied().unwrap_or(""),
+            slot => POINTER_NAMES[slot - GENERAL_REGISTERS].get(view).copied().unwrap_or("")
+        }
+    }
+    /// Width of the view in bits.
+    pub fn bits(self) -> u32 {
+        match self.view {
+            RegisterView::Full => 32,
+            RegisterView::Word => 16,
+            RegisterView::Low | RegisterView::High => 8
+        }
+    }
+    /// Reads this view out of the full register value.
+    pub fn get(self, raw : u32) -> u32 {
+        match self.view {
+            RegisterView::Full => raw,
+            RegisterView::Word => raw & 0xFFFF,
+            RegisterView::Low => raw & 0xFF,
+            RegisterView::High => (raw >> 8) & 0xFF
+        }
+    }
+    /// Writes `value`, truncated to the view's width, into the full register value.
+    pub fn set(self, raw : &mut u32, value : u32) {
+        *raw = match self.view {
+            RegisterView::Full => value,
+            RegisterView::Word => (*raw & !0xFFFF) | (value & 0xFFFF),
+            RegisterView::Low => (*raw & !0xFF) | (value & 0xFF),
+            RegisterView::High => (*raw & !0xFF00) | ((value & 0xFF) << 8)
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    /// Value held in a register, `rax`.
+    Register(Register),
+    /// Literal value, `#12` or a bare `12`.
+    Immediate(u32),
+    /// Memory byte, `[rax]`, `[rax+4]`, `[rax+rbx]` or `[12]`.
+    Memory(MemRef),
+    /// Jump target, resolved from the label table to an instruction index.
+    Label(&'static str, usize)
+}
+
+/// Address of a memory operand, the sum of whichever parts are present.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemRef {
+    pub base : Option<Register>,
+    pub index : Option<Register>,
+    pub offset : u32
+}
+
+impl MemRef {
+    /// `[12]`
+    pub fn absolute(addr : u32) -> Self {
+        MemRef { base: None, index: None, offset: addr }
+    }
+
+    // `[rax+rbx+4]`, `[rax]`, `[12]` and the legacy `*rax`/`*12`. Up to two registers and one
+    // offset, joined by `+` without spaces.
+    fn parse(token : &str) -> Option<Self> {
+        if let Some(rest) = token.strip_prefix('*') {
+            return match Register::parse(rest) {
+                Some(reg) => Some(MemRef { base: Some(reg), index: None, offset: 0 }),
+                None => rest.parse::<u32>().ok().map(MemRef::absolute)
+            };
+        }
+
+        let inner = token.strip_prefix('[')?.strip_suffix(']')?;
+        let mut mem = MemRef::absolute(0);
+        let mut offset = None;
+        for part in inner.split('+') {
+            match (Register::parse(part), part.parse::<u32>()) {
+                (Some(reg), _) if mem.base.is_none() => mem.base = Some(reg),
+                (Some(reg), _) if mem.index.is_none() => mem.index = Some(reg),
+                (None, Ok(value)) if offset.is_none() => offset = Some(value),
+                _ => return None
+            }
+        }
+        mem.offset = offset.unwrap_or(0);
+        Some(mem)
+    }
+}
+
+impl fmt::Display for MemRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts : Vec<String> = self.base.iter().chain(self.index.iter())
+            .map(|reg| reg.name().to_owned())
+            .collect();
+        if self.offset != 0 || parts.is_empty() {
+            parts.push(self.offset.to_string());
+        }
+        write!(f, "[{}]", parts.join("+"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    And,
+    Or,
+    Xor,
+    /// Shift left by src2 bits.
+    Shl,
+    /// Logical shift right.
+    Shr,
+    /// Arithmetic shift right, copying the sign bit in.
+    Sar,
+    /// Rotate left by src2 bits.
+    Rol,
+    Ror
+}
+
+const ARITH_OPS : [(ArithOp, &str); 12] = [
+    (ArithOp::Add, "add"),
+    (ArithOp::Sub, "sub"),
+    (ArithOp::Mul, "mul"),
+    (ArithOp::Div, "div"),
+    (ArithOp::And, "and"),
+    (ArithOp::Or, "or"),
+    (ArithOp::Xor, "xor"),
+    (ArithOp::Shl, "shl"),
+    (ArithOp::Shr, "shr"),
+    (ArithOp::Sar, "sar"),
+    (ArithOp::Rol, "rol"),
+    (ArithOp::Ror, "ror")
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    /// `grt dest lhs rhs` - rhs > lhs
+    Greater,
+    /// `lt dest lhs rhs` - rhs < lhs
+    Less,
+    /// `eq dest lhs rhs`
+    Equal
+}
+
+/// Branch condition, tested against the flags left by `cmp` or arithmetic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Zero,
+    NotZero,
+    Equal,
+    NotEqual,
+    /// Signed <
+    Less,
+    /// Signed <=
+    LessEqual,
+    /// Signed >
+    Greater,
+    /// Signed >=
+    GreaterEqual,
+    /// Unsigned <
+    Below,
+    /// Unsigned <=
+    BelowEqual,
+    /// Unsigned >
+    Above,
+    /// Unsigned >=
+    AboveEqual
+}
+
+// Jump and conditional call mnemonics for each condition.
+const CONDITIONS : [(Condition, &str, &str); 12] = [
+    (Condition::Zero, "jz", "callz"),
+    (Condition::NotZero, "jnz", "callnz"),
+    (Condition::Equal, "je", "calle"),
+    (Condition::NotEqual, "jne", "callne"),
+    (Condition::Less, "jl", "calll"),
+    (Condition::LessEqual, "jle", "callle"),
+    (Condition::Greater, "jg", "callg"),
+    (Condition::GreaterEqual, "jge", "callge"),
+    (Condition::Below, "jb", "callb"),
+    (Condition::BelowEqual, "jbe", "callbe"),
+    (Condition::Above, "ja", "calla"),
+    (Condition::AboveEqual, "jae", "callae")
+];
+
+impl Condition {
+    fn names(self) -> (&'static str, &'static str) {
+        CONDITIONS.iter()
+            .find(|(cond, ..)| *cond == self)
+            .map(|(_, jump, call)| (*jump, *call))
+            .unwrap_or(("", ""))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Dmp,
+    Panic,
+    Fault,
+    Memset { address : usize, value : u8 },
+    Mov { dest : Operand, src : Operand },
+    /// Little-endian read of `bytes` (2 or 4) bytes, `load16 rax [rbx]`.
+    Load { bytes : u8, dest : Register, src : MemRef },
+    /// Little-endian write of the low `bytes` (2 or 4) bytes of `src`, `store32 [rbx] rax`.
+    Store { bytes : u8, dest : MemRef, src : Operand },
+    Arith { op : ArithOp, src1 : Operand, src2 : Operand, dest : Operand },
+    /// Bitwise complement, `not src dest`.
+    Not { src : Operand, dest : Operand },
+    Goto(Operand),
+    Cgt { reg : Register, target : Operand },
+    /// Sets the flags as for `lhs - rhs` without storing the result.
+    Cmp { lhs : Operand, rhs : Operand },
+    Jump { cond : Condition, target : Operand },
+    /// `call` taken only when the condition holds.
+    CallIf { cond : Condition, target : Operand },
+    Compare { op : CompareOp, dest : Operand, lhs : Operand, rhs : Operand },
+    OutStr(Operand),
+    OutByte(Operand),
+    Vfsr { pointer : Operand, file : u8 },
+    Vrlx(u8),
+    Inv(Register),
+    Push(Operand),
+    Pop(Operand),
+    Call(Operand),
+    Esr,
+    /// Copies the flags register into a register.
+    Rdf(Register),
+    Pushf,
+    Popf,
+    /// Stops the machine with an exit code, `hlt` alone exits with 0.
+    Hlt(Operand),
+    /// Software interrupt, runs the handler installed for the vector.
+    Int(u8),
+    /// Returns from an interrupt handler, restoring the flags.
+    Iret,
+    /// Installs a handler in the interrupt vector table, `ivt 0 on_divide`.
+    Ivt { vector : u8, target : Operand },
+    /// Calls the host's syscall handler.
+    Sys(u32),
+    /// Reads a byte of input, waiting for one. End of input reads as `u32::MAX`, truncated to the
+    /// destination, and sets the carry flag, which is cleared otherwise.
+    InByte(Operand),
+    /// 1 when a byte of input (or the end of it) can be read without waiting, 0 otherwise.
+    Poll(Operand),
+    /// Reads a line of at most `max` bytes into `buf`, leaving the length in `dest`. The newline is
+    /// consumed but not stored; end of input before any byte gives `u32::MAX` and sets the carry
+    /// flag, which is cleared otherwise.
+    ReadLine { buf : MemRef, max : Operand, dest : Operand }
+}
+
+impl Instruction {
+    /// Every register the instruction names, including those used to address memory.
+    pub fn registers(&self) -> Vec<Register> {
+        let operands : Vec<Operand> = match *self {
+            Instruction::Mov { dest, src }
+            | Instruction::Not { src, dest } => vec![dest, src],
+            Instruction::Load { dest, src, .. } => vec![Operand::Register(dest), Operand::Memory(src)],
+            Instruction::Store { dest, src, .. } => vec![Operand::Memory(dest), src],
+            Instruction::Arith { src1, src2, dest, .. } => vec![src1, src2, dest],
+            Instruction::Cgt { reg, target } => vec![Operand::Register(reg), target],
+            Instruction::Cmp { lhs, rhs } => vec![lhs, rhs],
+            Instruction::Compare { dest, lhs, rhs, .. } => vec![dest, lhs, rhs],
+            Instruction::Vfsr { pointer, .. } => vec![pointer],
+            Instruction::Inv(reg) | Instruction::Rdf(reg) => vec![Operand::Register(reg)],
+            Instruction::Goto(op)
+            | Instruction::Jump { target : op, .. }
+            | Instruction::CallIf { target : op, .. }
+            | Instruction::OutStr(op)
+            | Instruction::OutByte(op)
+            | Instruction::Push(op)
+            | Instruction::Pop(op)
+            | Instruction::Call(op)
+            | Instruction::Hlt(op)
+            | Instruction::InByte(op)
+            | Instruction::Poll(op)
+            | Instruction::Ivt { target : op, .. } => vec![op],
+            Instruction::ReadLine { buf, max, dest } => vec![Operand::Memory(buf), max, dest],
+            Instruction::Dmp
+            | Instruction::Panic
+            | Instruction::Fault
+            | Instruction::Memset { .. }
+            | Instruction::Vrlx(_)
+            | Instruction::Esr
+            | Instruction::Pushf
+            | Instruction::Popf
+            | Instruction::Int(_)
+            | Instruction::Iret
+            | Instruction::Sys(_) => Vec::new()
+        };
+
+        operands.into_iter()
+            .flat_map(|op| match op {
+                Operand::Register(reg) => vec![reg],
+                Operand::Memory(mem) => mem.base.into_iter().chain(mem.index).collect(),
+                Operand::Immediate(_) | Operand::Label(..) => Vec::new()
+            })
+            .collect()
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::Dmp => "dmp",
+            Instruction::Panic => "panic",
+            Instruction::Fault => "fault",
+            Instruction::Memset { .. } => "memset",
+            Instruction::Mov { .. } => "mov",
+            Instruction::Load { bytes, .. } => if *bytes == 2 { "load16" } else { "load32" },
+            Instruction::Store { bytes, .. } => if *bytes == 2 { "store16" } else { "store32" },
+            Instruction::Arith { op, .. } => ARITH_OPS.iter()
+                .find(|(o, _)| o == op)
+                .map_or("", |(_, name)| name),
+            Instruction::Not { .. } => "not",
+            Instruction::Goto(_) => "goto",
+            Instruction::Cgt { .. } => "cgt",
+            Instruction::Cmp { .. } => "cmp",
+            Instruction::Jump { cond, .. } => cond.names().0,
+            Instruction::CallIf { cond, .. } => cond.names().1,
+            Instruction::Compare { op, .. } => match op {
+                CompareOp::Greater => "grt",
+                CompareOp::Less => "lt",
+                CompareOp::Equal => "eq"
+            },
+            Instruction::OutStr(_) => "outstr",
+            Instruction::OutByte(_) => "outbyte",
+            Instruction::Vfsr { .. } => "vfsr",
+            Instruction::Vrlx(_) => "vrlx",
+            Instruction::Inv(_) => "inv",
+            Instruction::Push(_) => "push",
+            Instruction::Pop(_) => "pop",
+            Instruction::Call(_) => "call",
+            Instruction::Esr => "esr",
+            Instruction::Rdf(_) => "rdf",
+            Instruction::Pushf => "pushf",
+            Instruction::Popf => "popf",
+            Instruction::Hlt(_) => "hlt",
+            Instruction::Int(_) => "int",
+            Instruction::Iret => "iret",
+            Instruction::Ivt { .. } => "ivt",
+            Instruction::Sys(_) => "sys",
+            Instruction::InByte(_) => "inbyte",
+            Instruction::Poll(_) => "poll",
+            Instruction::ReadLine { .. } => "readln"
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Register(reg) => write!(f, "{}", reg.name()),
+            Operand::Immediate(value) => write!(f, "#{}", value),
+            Operand::Memory(mem) => write!(f, "{}", mem),
+            Operand::Label(name, _) => write!(f, "{}", name)
+        }
+    }
+}
+
+// Renders back to source form, which the decoder accepts again.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.mnemonic())?;
+        match self {
+            Instruction::Memset { address, value } => write!(f, " {} {}", address, value),
+            Instruction::Mov { dest, src } => write!(f, " {} {}", dest, src),
+            Instruction::Load { dest, src, .. } => write!(f, " {} {}", dest.name(), src),
+            Instruction::Store { dest, src, .. } => write!(f, " {} {}", dest, src),
+            Instruction::Arith { src1, src2, dest, .. } => write!(f, " {} {} {}", src1, src2, dest),
+            Instruction::Not { src, dest } => write!(f, " {} {}", src, dest),
+            Instruction::Cgt { reg, target } => write!(f, " {} {}", reg.name(), target),
+            Instruction::Cmp { lhs, rhs } => write!(f, " {} {}", lhs, rhs),
+            Instruction::Jump { target, .. } | Instruction::CallIf { target, .. } => write!(f, " {}", target),
+            Instruction::Compare { dest, lhs, rhs, .. } => write!(f, " {} {} {}", dest, lhs, rhs),
+            Instruction::Vfsr { pointer, file } => write!(f, " {} {}", pointer, file),
+            Instruction::Vrlx(fid) => write!(f, " {}", fid),
+            Instruction::Int(vector) => write!(f, " {}", vector),
+            Instruction::Sys(number) => write!(f, " {}", number),
+            Instruction::ReadLine { buf, max, dest } => write!(f, " {} {} {}", buf, max, dest),
+            Instruction::Ivt { vector, target } => write!(f, " {} {}", vector, target),
+            Instruction::Inv(reg) | Instruction::Rdf(reg) => write!(f, " {}", reg.name()),
+            Instruction::Goto(op)
+            | Instruction::OutStr(op)
+            | Instruction::OutByte(op)
+            | Instruction::Push(op)
+            | Instruction::Pop(op)
+            | Instruction::Call(op)
+            | Instruction::Hlt(op)
+            | Instruction::InByte(op)
+            | Instruction::Poll(op) => write!(f, " {}", op),
+            Instruction::Dmp
+            | Instruction::Panic
+            | Instruction::Fault
+            | Instruction::Esr
+            | Instruction::Pushf
+            | Instruction::Popf
+            | Instruction::Iret => Ok(())
+        }
+    }
+}
+
+/// A decoded program, with labels resolved to instruction indices.
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub instructions : Vec<Instruction>,
+    pub labels : HashMap<String, usize>
+}
+
+impl Program {
+    /// Nearest label at or before `index`, with the distance from it.
+    pub fn label_at(&self, index : usize) -> Option<(&str, usize)> {
+        self.labels.iter()
+            .filter(|(_, position)| **position <= index)
+            .max_by_key(|(name, position)| (**position, std::cmp::Reverse(name.as_str())))
+            .map(|(name, position)| (name.as_str(), index - position))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DecodeError {
+    pub token_index : usize,
+    pub token : String,
+    pub code : DecodeErrorCode
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parsed_err = match self.code {
+            DecodeErrorCode::UnknownInstruction => "Unrecognized instruction",
+            DecodeErrorCode::MissingOperand => "Missing operand after",
+            DecodeErrorCode::InvalidOperand => "Invalid operand",
+            DecodeErrorCode::UndefinedLabel => "Undefined label",
+            DecodeErrorCode::UnclosedComment => "Comment is never closed at"
+        };
+        write!(f, "Decode error: {} `{}` at token #{}", parsed_err, self.token, self.token_index)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeErrorCode {
+    UnknownInstruction,
+    MissingOperand,
+    InvalidOperand,
+    UndefinedLabel,
+    UnclosedComment
+}
+
+struct Decoder<'a> {
+    tokens : &'a [&'static str],
+    label_table : &'a HashMap<String, usize>,
+    pos : usize,
+    // Token index of the instruction currently being decoded.
+    start : usize
+}
+
+impl<'a> Decoder<'a> {
+    fn error(&self, index : usize, code : DecodeErrorCode) -> DecodeError {
+        DecodeError {
+            token_index: index,
+            token: self.tokens.get(index).copied().unwrap_or("").to_owned(),
+            code
+        }
+    }
+
+    fn next(&mut self) -> Result<(usize, &'static str), DecodeError> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                Ok((self.pos - 1, token))
+            },
+            None => Err(self.error(self.start, DecodeErrorCode::MissingOperand))
+        }
+    }
+
+    fn operand<T>(&mut self, parse : impl Fn(&'static str) -> Option<T>) -> Result<T, DecodeError> {
+        let (index, token) = self.next()?;
+        parse(token).ok_or_else(|| self.error(index, DecodeErrorCode::InvalidOperand))
+    }
+
+    fn register(&mut self) -> Result<Register, DecodeError> {
+        self.operand(Register::parse)
+    }
+
+    fn number<T : std::str::FromStr>(&mut self) -> Result<T, DecodeError> {
+        self.operand(|token| token.parse::<T>().ok())
+    }
+
+    fn label(&mut self) -> Result<Operand, DecodeError> {
+        let (index, token) = self.next()?;
+        match self.label_table.get(token) {
+            // Fixed up to an instruction index once the whole stream is decoded.
+            Some(position) => Ok(Operand::Label(token, *position)),
+            None => Err(self.error(index, DecodeErrorCode::UndefinedLabel))
+        }
+    }
+
+    // Label, or a register holding an instruction index.
+    fn target(&mut self) -> Result<Operand, DecodeError> {
+        let checkpoint = self.pos;
+        match self.register() {
+            Ok(reg) => Ok(Operand::Register(reg)),
+            Err(_) => {
+                self.pos = checkpoint;
+                self.label()
+            }
+        }
+    }
+
+    fn instruction(&mut self, code : &'static str) -> Result<Option<Instruction>, DecodeError> {
+        let instruction = match code {
+            "label" => {
+                self.next()?;
+                return Ok(None);
+            },
+            "//" => {
+                loop {
+                    let (_, token) = self.next()
+                        .map_err(|_| self.error(self.start, DecodeErrorCode::UnclosedComment))?;
+                    if token == "//" {
+                        return Ok(None);
+                    }
+                }
+            },
+            "dmp" => Instruction::Dmp,
+            "panic" => Instruction::Panic,
+            "fault" => Instruction::Fault,
+            "memset" => Instruction::Memset {
+                address: self.number()?,
+                value: self.number()?
+            },
+            "mov" => Instruction::Mov {
+                dest: self.operand(place)?,
+                src: self.operand(value)?
+            },
+            "load16" | "load32" => Instruction::Load {
+                bytes: if code == "load16" { 2 } else { 4 },
+                dest: self.register()?,
+                src: self.operand(MemRef::parse)?
+            },
+            "store16" | "store32" => Instruction::Store {
+                bytes: if code == "store16" { 2 } else { 4 },
+                dest: self.operand(MemRef::parse)?,
+                src: self.operand(value)?
+            },
+            "not" => Instruction::Not {
+                src: self.operand(value)?,
+                dest: self.operand(place)?
+            },
+            "goto" => Instruction::Goto(self.target()?),
+            "cgt" => Instruction::Cgt {
+                reg: self.register()?,
+                target: self.label()?
+            },
+            "cmp" => Instruction::Cmp {
+                lhs: self.operand(value)?,
+                rhs: self.operand(value)?
+            },
+            "grt" | "lt" | "eq" => Instruction::Compare {
+                op: match code {
+                    "grt" => CompareOp::Greater,
+                    "lt" => CompareOp::Less,
+                    _ => CompareOp::Equal
+                },
+                dest: self.operand(place)?,
+                lhs: self.operand(value)?,
+                rhs: self.operand(value)?
+            },
+            "outstr" => Instruction::OutStr(self.operand(value)?),
+            "outbyte" => Instruction::OutByte(self.operand(value)?),
+            "vfsr" => Instruction::Vfsr {
+                pointer: self.operand(value)?,
+                file: self.number()?
+            },
+            "vrlx" => Instruction::Vrlx(self.number()?),
+            "inv" => Instruction::Inv(self.register()?),
+            "push" => Instruction::Push(self.operand(value)?),
+            "pop" => Instruction::Pop(self.operand(place)?),
+            "call" => Instruction::Call(self.target()?),
+            "esr" => Instruction::Esr,
+            "rdf" => Instruction::Rdf(self.register()?),
+            "pushf" => Instruction::Pushf,
+            "popf" => Instruction::Popf,
+            "int" => Instruction::Int(self.number()?),
+            "iret" => Instruction::Iret,
+            "sys" => Instruction::Sys(self.number()?),
+            "inbyte" => Instruction::InByte(self.operand(place)?),
+            "poll" => Instruction::Poll(self.operand(place)?),
+            "readln" => Instruction::ReadLine {
+                buf: self.operand(MemRef::parse)?,
+                max: self.operand(value)?,
+                dest: self.operand(place)?
+            },
+            "ivt" => Instruction::Ivt {
+                vector: self.number()?,
+                target: self.target()?
+            },
+            // The code is optional, the next token is only taken when it reads as a value.
+            "hlt" => match self.tokens.get(self.pos).and_then(|t| value(t)) {
+                Some(code) => {
+                    self.pos += 1;
+                    Instruction::Hlt(code)
+                },
+                None => Instruction::Hlt(Operand::Immediate(0))
+            },
+            _ if ARITH_OPS.iter().any(|(_, name)| *name == code) => Instruction::Arith {
+                op: ARITH_OPS.iter().find(|(_, name)| *name == code).map_or(ArithOp::Add, |(op, _)| *op),
+                src1: self.operand(value)?,
+                src2: self.operand(value)?,
+                dest: self.operand(place)?
+            },
+            _ => match CONDITIONS.iter().find(|(_, jump, call)| *jump == code || *call == code) {
+                Some((cond, jump, _)) if *jump == code => Instruction::Jump { cond: *cond, target: self.target()? },
+                Some((cond, ..)) => Instruction::CallIf { cond: *cond, target: self.target()? },
+                None => return Err(self.error(self.start, DecodeErrorCode::UnknownInstruction))
+            }
+        };
+
+        Ok(Some(instruction))
+    }
+}
+
+/// Whether `token` reads as a value operand (register, immediate or memory), which is how optional
+/// operands are told apart from the next instruction.
+pub fn is_value(token : &str) -> bool {
+    value(token).is_some()
+}
+
+// Anything that can be read: a register, an immediate or memory.
+fn value(token : &str) -> Option<Operand> {
+    Register::parse(token).map(Operand::Register)
+        .or_else(|| token.strip_prefix('#').unwrap_or(token).parse::<u32>().ok().map(Operand::Immediate))
+        .or_else(|| MemRef::parse(token).map(Operand::Memory))
+}
+
+// Anything that can be written: a register or memory.
+fn place(token : &str) -> Option<Operand> {
+    Register::parse(token).map(Operand::Register)
+        .or_else(|| MemRef::parse(token).map(Operand::Memory))
+}
+
+/// Decodes the single instruction starting at token `pos`, returning it along with the position of
+/// the token after it. Comments and `label` markers decode to `None`. Label operands keep the
+/// token index from the label table rather than an instruction index.
+pub fn decode_one(assembly : &Assembly, pos : usize) -> Result<(Option<Instruction>, usize), DecodeError> {
+    let mut decoder = Decoder {
+        tokens: &assembly.tokens,
+        label_table: &assembly.label_table,
+        pos,
+        start: pos
+    };
+
+    let (_, code) = decoder.next()?;
+    let instruction = decoder.instruction(code)?;
+    Ok((instruction, decoder.pos))
+}
+
+/// Decodes the token stream of an `Assembly` into typed instructions.
+///
+/// Comments and `label` markers produce no instructions; label table entries, which point at
+/// token indices, are mapped to the first instruction at or after that token.
+pub fn decode(assembly : &Assembly) -> Result<Program, DecodeError> {
+    let mut decoder = Decoder {
+        tokens: &assembly.tokens,
+        label_table: &assembly.label_table,
+        pos: 0,
+        start: 0
+    };
+
+    let mut instructions : Vec<Instruction> = Vec::new();
+    let mut starts : Vec<usize> = Vec::new();
+
+    while decoder.pos < decoder.tokens.len() {
+        decoder.start = decoder.pos;
+        let (_, code) = decoder.next()?;
+        if let Some(instruction) = decoder.instruction(code)? {
+            instructions.push(instruction);
+            starts.push(decoder.start);
+        }
+    }
+
+    let resolve = |token_index : usize| starts.partition_point(|start| *start < token_index);
+
+    for instruction in instructions.iter_mut() {
+        match instruction {
+            Instruction::Goto(Operand::Label(_, target))
+            | Instruction::Cgt { target : Operand::Label(_, target), .. }
+            | Instruction::Call(Operand::Label(_, target))
+            | Instruction::Jump { target : Operand::Label(_, target), .. }
+            | Instruction::CallIf { target : Operand::Label(_, target), .. }
+            | Instruction::Ivt { target : Operand::Label(_, target), .. } => {
+                *target = resolve(*target);
+            },
+            _ => {}
+        }
+    }
+
+    let labels = assembly.label_table.iter()
+        .map(|(name, index)| (name.clone(), resolve(*index)))
+        .collect();
+
+    Ok(Program {
+        instructions,
+        labels
+    })
+}
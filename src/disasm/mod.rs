@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+
+use crate::decoder::{self, DecodeErrorCode};
+use crate::tokenizer::Assembly;
+
+pub struct Disassembly {
+    /// Source with one instruction per line, reassemblable by `assembler::assemble`.
+    pub text : String,
+    /// Number of unknown opcodes, malformed operands and label table inconsistencies flagged in `text`.
+    pub problems : usize
+}
+
+/// Reconstructs readable source from an image's token stream.
+///
+/// `label` lines are placed at the indices recorded in the label table. Anything that doesn't
+/// decode is kept as raw tokens followed by a `// ... //` comment describing the problem, and
+/// label table inconsistencies get a comment line of their own.
+pub fn disassemble(assembly : &Assembly) -> Disassembly {
+    let tokens = &assembly.tokens;
+    let mut out = Disassembly {
+        text: String::new(),
+        problems: 0
+    };
+
+    // Token index -> labels recorded there.
+    let mut labels : BTreeMap<usize, Vec<&str>> = BTreeMap::new();
+    for (name, index) in &assembly.label_table {
+        labels.entry(*index).or_default().push(name);
+    }
+    for names in labels.values_mut() {
+        names.sort();
+    }
+
+    let mut pos = 0;
+    while pos < tokens.len() {
+        let (next, line) = match decoder::decode_one(assembly, pos) {
+            Ok((Some(instruction), next)) => (next, format!("    {}", instruction)),
+            Ok((None, next)) => {
+                // Labels come from the table, only complain about markers it doesn't agree with.
+                if tokens[pos] == "label" && next > pos + 1 {
+                    let name = tokens[pos + 1];
+                    match assembly.label_table.get(name) {
+                        Some(index) if *index == pos => {},
+                        Some(index) => out.note(&format!("label {} at token {} but the label table places it at token {}", name, pos, index)),
+                        None => out.note(&format!("label {} at token {} is missing from the label table", name, pos))
+                    }
+                }
+                pos = next;
+                continue;
+            },
+            Err(e) if e.code == DecodeErrorCode::UnknownInstruction => {
+                (pos + 1, flagged(&tokens[pos..pos + 1], "unknown opcode", &mut out.problems))
+            },
+            Err(e) => {
+                let end = match e.code {
+                    DecodeErrorCode::UnclosedComment => tokens.len(),
+                    _ => (e.token_index + 1).clamp(pos + 1, tokens.len())
+                };
+                let problem = match e.code {
+                    DecodeErrorCode::MissingOperand => "missing operand".to_owned(),
+                    DecodeErrorCode::UndefinedLabel => format!("undefined label {}", e.token),
+                    DecodeErrorCode::UnclosedComment => "unclosed comment".to_owned(),
+                    _ => format!("malformed operand {}", e.token)
+                };
+                (end, flagged(&tokens[pos..end], &problem, &mut out.problems))
+            }
+        };
+
+        // Labels before this instruction, and any that point into the middle of it.
+        let inside : Vec<usize> = labels.range(..next).map(|(index, _)| *index).collect();
+        for index in inside {
+            for name in labels.remove(&index).unwrap_or_default() {
+                if index > pos {
+                    out.note(&format!("label {} points inside an instruction at token {}", name, index));
+                } else {
+                    out.line(&format!("label {}", name));
+                }
+            }
+        }
+
+        out.line(&line);
+        pos = next;
+    }
+
+    for (index, names) in labels {
+        for name in names {
+            if index > tokens.len() {
+                out.note(&format!("label {} points past the end of the program at token {}", name, index));
+            } else {
+                out.line(&format!("label {}", name));
+            }
+        }
+    }
+
+    out
+}
+
+impl Disassembly {
+    fn line(&mut self, line : &str) {
+        self.text.push_str(line);
+        self.text.push('\n');
+    }
+
+    // Label problems are reported as a whole-line comment so the output still assembles.
+    fn note(&mut self, problem : &str) {
+        self.problems += 1;
+        self.line(&format!("// {} //", problem));
+    }
+}
+
+fn flagged(tokens : &[&str], problem : &str, problems : &mut usize) -> String {
+    *problems += 1;
+    format!("    {} // {} //", tokens.join(" "), problem)
+}
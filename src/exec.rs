@@ -1,657 +1,1540 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::fmt;
 
+use crate::console::{InputSource, OutputSink, StdinInput, StdoutOutput};
+use crate::decoder::{self, ArithOp, CompareOp, Condition, DecodeError, DecodeErrorCode, Instruction, MemRef, Operand, Program, Register, GENERAL_REGISTERS};
+use crate::syscall::{SyscallError, SyscallHandler};
 use crate::tokenizer::{Assembly, self};
-use crate::vfs::VFS;
+use crate::trace::{TraceRecord, Tracer};
+use crate::vfs::{VfsError, VFS};
 
-// Memory size in bytes
-const MEM_SIZE : usize = 512;
+/// Set when a result is zero.
+pub const FLAG_ZERO : u32 = 0b0001;
+/// Set when an unsigned result doesn't fit the destination, or `sub` borrows.
+pub const FLAG_CARRY : u32 = 0b0010;
+/// Copy of the result's top bit.
+pub const FLAG_SIGN : u32 = 0b0100;
+/// Set when a signed result doesn't fit the destination.
+pub const FLAG_OVERFLOW : u32 = 0b1000;
+const FLAG_MASK : u32 = FLAG_ZERO | FLAG_CARRY | FLAG_SIGN | FLAG_OVERFLOW;
+const FLAG_NAMES : [(u32, char); 4] = [(FLAG_ZERO, 'Z'), (FLAG_CARRY, 'C'), (FLAG_SIGN, 'S'), (FLAG_OVERFLOW, 'O')];
 
-const DUMP_VFS : bool = false;
+/// Renders flags as `ZCSO`, with `-` in place of clear bits.
+pub fn format_flags(flags : u32) -> String {
+    FLAG_NAMES.iter()
+        .map(|(bit, name)| if flags & bit != 0 { *name } else { '-' })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpVerbosity {
+    /// Registers only.
+    Registers,
+    /// Registers and every byte of memory.
+    Memory,
+    /// Registers, memory and the contents of the VFS.
+    Full
+}
+
+/// Number of entries in the interrupt vector table.
+pub const IVT_VECTORS : usize = 32;
+/// `div` by zero.
+pub const VECTOR_DIVIDE_BY_ZERO : u8 = 0;
+/// Unknown instruction or bad operand, including images loaded by `vrlx`.
+pub const VECTOR_INVALID_OPCODE : u8 = 1;
+/// Out of bounds or misaligned memory access.
+pub const VECTOR_SEGFAULT : u8 = 2;
+/// Stack underflow or overflow, on the data stack or the call stack.
+pub const VECTOR_STACK : u8 = 3;
+/// The `fault` instruction.
+pub const VECTOR_FAULT : u8 = 4;
+
+/// Region of memory holding a guest-visible stack. It grows down from `base + size`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackRegion {
+    pub base : usize,
+    pub size : usize
+}
+
+impl StackRegion {
+    /// One past the highest stack address, where `rsp` starts.
+    pub fn top(&self) -> usize {
+        self.base + self.size
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MachineConfig {
+    /// Memory size in bytes.
+    pub mem_size : usize,
+    /// Minimum accessable memory address, will cause segmentation fault if read below this.
+    /// Consider this reserved for system use.
+    pub reserved : usize,
+    /// Unsafe behaviour - continue after fault (e.g Segmentation Fault etc)
+    /// Only use when absolutely certain of behaviour. Each skipped fault is reported through the
+    /// output sink.
+    pub continue_after_fault : bool,
+    /// What the `dmp` instruction prints.
+    pub dump : DumpVerbosity,
+    /// Maximum number of stack entries, `None` for unbounded. Only applies to the host-side stack.
+    pub stack_limit : Option<usize>,
+    /// Keep the data stack in memory as 4-byte entries addressed by `rsp`, instead of on the host.
+    pub stack_region : Option<StackRegion>,
+    /// Maximum number of nested calls and interrupts, `None` for unbounded.
+    pub call_limit : Option<usize>,
+    /// Address of the interrupt vector table, `IVT_VECTORS` 4-byte little-endian entries holding a
+    /// handler's instruction index plus one, 0 when none is installed. Best placed below `reserved`
+    /// so the guest can only change it through `ivt`. `None` disables interrupts.
+    pub ivt : Option<usize>,
+    /// Number of general registers, `r0` up to `r15`. Clamped to between 4 and 16 so the legacy
+    /// `rax`..`rdx` names are always available. Programs naming a register past this fail to load.
+    pub registers : usize
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        Self {
+            mem_size: 512,
+            reserved: 0x0,
+            continue_after_fault: false,
+            dump: DumpVerbosity::Memory,
+            stack_limit: None,
+            stack_region: None,
+            call_limit: None,
+            ivt: None,
+            registers: GENERAL_REGISTERS
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// Execution reached the end of the program.
+    Finished,
+    /// Stopped by `hlt` with this code.
+    Halted(u32)
+}
+
+impl ExitReason {
+    /// Exit status to report to the host. Hosts only keep the low byte, so codes above 255 are
+    /// reported as 255 rather than wrapping to something that may read as success.
+    pub fn code(&self) -> i32 {
+        match self {
+            ExitReason::Finished => 0,
+            ExitReason::Halted(code) => (*code).min(255) as i32
+        }
+    }
+}
 
-// Minimum accessable memory address, will cause segmentation fault if read below this.
-// Consider this reserved for system use.
-const RESERVED_MIN_MEM_ADDR : usize = 0x0;
+#[derive(Debug, Clone)]
+pub enum ExecErrorKind {
+    InvalidOpcode,
+    InvalidOperand,
+    UndefinedLabel,
+    StackUnderflow,
+    StackOverflow,
+    /// `esr` with no call to return from.
+    ReturnWithoutCall,
+    /// More nested calls than `MachineConfig::call_limit`.
+    CallStackOverflow,
+    Segfault(usize),
+    /// A multi-byte access at an address that isn't a multiple of its size.
+    Misaligned(usize),
+    DivideByZero,
+    VfsError(VfsError),
+    /// A file loaded by `vrlx` is not a valid image.
+    InvalidImage,
+    /// Writing the trace or other host output failed.
+    Io(String),
+    /// A jump past the end of the program.
+    JumpOutOfRange(usize),
+    /// `int` on a vector with no handler installed.
+    UnhandledInterrupt(u8),
+    /// `iret` outside of an interrupt handler.
+    ReturnWithoutInterrupt,
+    /// `sys` failed, or there is no handler registered.
+    Syscall(SyscallError),
+    /// `MachineConfig::stack_region` doesn't fit in usable memory or in `rsp`.
+    InvalidStackRegion(StackRegion),
+    /// Raised by the `fault` instruction.
+    Fault,
+    /// Raised by the `panic` instruction.
+    Panic
+}
+
+impl ExecErrorKind {
+    /// Interrupt vector the error is delivered to when the guest has a handler installed.
+    pub fn vector(&self) -> Option<u8> {
+        match self {
+            ExecErrorKind::DivideByZero => Some(VECTOR_DIVIDE_BY_ZERO),
+            ExecErrorKind::InvalidOpcode
+            | ExecErrorKind::InvalidOperand
+            | ExecErrorKind::InvalidImage => Some(VECTOR_INVALID_OPCODE),
+            ExecErrorKind::Segfault(_)
+            | ExecErrorKind::Misaligned(_) => Some(VECTOR_SEGFAULT),
+            ExecErrorKind::StackUnderflow
+            | ExecErrorKind::StackOverflow
+            | ExecErrorKind::ReturnWithoutCall
+            | ExecErrorKind::CallStackOverflow => Some(VECTOR_STACK),
+            ExecErrorKind::Fault => Some(VECTOR_FAULT),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExecError {
+    pub kind : ExecErrorKind,
+    /// Instruction index the error was raised at, or the token index for errors found while decoding.
+    pub index : usize,
+    /// Source form of the offending instruction or token.
+    pub token : String
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "!!! FAULTED !!!\n  Cause: {} at instr #{} (`{}`)", self.kind, self.index, self.token)
+    }
+}
+
+impl fmt::Display for ExecErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cause = match self {
+            ExecErrorKind::InvalidOpcode => "Unrecognized instruction".to_owned(),
+            ExecErrorKind::InvalidOperand => "Invalid operand".to_owned(),
+            ExecErrorKind::UndefinedLabel => "Undefined label".to_owned(),
+            ExecErrorKind::StackUnderflow => "Stack underflow - no elements remaining to pop".to_owned(),
+            ExecErrorKind::StackOverflow => "Stack overflow".to_owned(),
+            ExecErrorKind::ReturnWithoutCall => "Return with no active call".to_owned(),
+            ExecErrorKind::CallStackOverflow => "Call stack overflow".to_owned(),
+            ExecErrorKind::Segfault(addr) => format!("Segmentation fault - Accessed memory out of bounds. Address: {}", addr),
+            ExecErrorKind::Misaligned(addr) => format!("Misaligned access - Address {} is not a multiple of the access size", addr),
+            ExecErrorKind::DivideByZero => "Divide by zero".to_owned(),
+            ExecErrorKind::VfsError(e) => e.to_string(),
+            ExecErrorKind::InvalidImage => "Failed to load program image".to_owned(),
+            ExecErrorKind::Io(e) => format!("I/O error: {}", e),
+            ExecErrorKind::JumpOutOfRange(target) => format!("Jump target {} is past the end of the program", target),
+            ExecErrorKind::UnhandledInterrupt(vector) => format!("No handler installed for interrupt {}", vector),
+            ExecErrorKind::ReturnWithoutInterrupt => "Return with no active interrupt".to_owned(),
+            ExecErrorKind::Syscall(e) => e.to_string(),
+            ExecErrorKind::InvalidStackRegion(region) => format!("Stack region {}:{} is outside usable memory, past 2^32 or not a multiple of 4", region.base, region.size),
+            ExecErrorKind::Fault => "Fault requested".to_owned(),
+            ExecErrorKind::Panic => "Panic requested by instruction set".to_owned()
+        };
+        f.write_str(&cause)
+    }
+}
 
-// Unsafe behaviour - continue after fault (e.g Segmentation Fault etc)
-// Only use when absolutely certain of behaviour.
-const CONTINUE_AFTER_FAULT : bool = false;
+impl From<DecodeError> for ExecError {
+    fn from(e : DecodeError) -> Self {
+        let kind = match e.code {
+            DecodeErrorCode::UnknownInstruction => ExecErrorKind::InvalidOpcode,
+            DecodeErrorCode::UndefinedLabel => ExecErrorKind::UndefinedLabel,
+            DecodeErrorCode::MissingOperand
+            | DecodeErrorCode::InvalidOperand
+            | DecodeErrorCode::UnclosedComment => ExecErrorKind::InvalidOperand
+        };
+        ExecError {
+            kind,
+            index: e.token_index,
+            token: e.token
+        }
+    }
+}
+
+// Where to resume once an interrupt handler runs `iret`.
+struct InterruptFrame {
+    ret : usize,
+    flags : u32,
+    // Raised by an error rather than `int`, a second one while this is active isn't delivered.
+    exception : bool
+}
 
 pub struct Executor {
     index : usize,
-    tokens : Vec<&'static str>,
-    label_table: HashMap<String, usize>,
+    program : Program,
+    // Programs suspended by `vrlx`, resumed after the instruction that switched away from them.
+    contexts : Vec<(Program, usize)>,
     vfs : VFS,
-    rax : u8,
-    rbx : u8,
-    rcx : u8,
-    rdx : u8,
-    mem : [u8; MEM_SIZE],
-    stack : Vec<usize>
+    config : MachineConfig,
+    registers : [u32; Register::ALL.len()],
+    flags : u32,
+    mem : Vec<u8>,
+    stack : Vec<usize>,
+    // Return indices of active calls, kept apart from the data stack so `push`/`pop` can't disturb them.
+    calls : Vec<usize>,
+    interrupts : Vec<InterruptFrame>,
+    tracer : Option<Tracer>,
+    syscalls : Option<Box<dyn SyscallHandler>>,
+    input : Box<dyn InputSource>,
+    output : Box<dyn OutputSink>,
+    // Memory writes made by the current instruction, only collected while tracing.
+    mem_writes : Option<Vec<(usize, u8, u8)>>,
+    steps : u64,
+    // Set by `hlt`, the machine stays stopped afterwards.
+    halted : Option<u32>,
+    // Faults let through by `continue_after_fault`, written to the output once the instruction
+    // finishes. Memory reads take `&self`, hence the cell.
+    skipped : RefCell<Vec<ExecError>>
 }
 
 impl Executor {
-    pub fn new(vasm : Assembly, vfs : VFS) -> Self {
-        Self {
+    /// Console input starts out as `StdinInput` and output as `StdoutOutput`. Stdin is only
+    /// touched once the guest reads or polls input; see `StdinInput` for how it shares stdin with
+    /// the host, or pass another source to `set_input` first.
+    pub fn new(vasm : Assembly, vfs : VFS, mut config : MachineConfig) -> Result<Self, ExecError> {
+        config.registers = config.registers.clamp(4, GENERAL_REGISTERS);
+        if let Some(region) = config.stack_region {
+            check_stack_region(region, &config)?;
+        }
+        let program = decoder::decode(&vasm)?;
+        check_registers(&program, config.registers)?;
+
+        let mut exec = Self {
             index: 0,
-            tokens: vasm.tokens,
-            label_table: vasm.label_table,
+            program,
+            contexts: Vec::new(),
             vfs,
-            rax : 0,
-            rbx : 0,
-            rcx : 0,
-            rdx : 0,
-            mem : [0 ; MEM_SIZE],
-            stack : Vec::new()
+            registers : [0; Register::ALL.len()],
+            flags: 0,
+            mem : vec![0 ; config.mem_size],
+            stack : Vec::new(),
+            calls: Vec::new(),
+            interrupts: Vec::new(),
+            tracer: None,
+            syscalls: None,
+            input: Box::new(StdinInput::new()),
+            output: Box::new(StdoutOutput),
+            mem_writes: None,
+            steps: 0,
+            halted: None,
+            skipped: RefCell::new(Vec::new()),
+            config
+        };
+
+        if let Some(region) = exec.config.stack_region {
+            exec.set_register(Register::RSP, region.top() as u32);
+            exec.set_register(Register::RBP, region.top() as u32);
         }
+        Ok(exec)
     }
-    pub fn run(&mut self) {
-        let len = self.tokens.len();
+
+    /// Replaces the running program and starts it from the first instruction.
+    /// Registers, memory, the stack and the VFS are kept. Return addresses, interrupt frames and
+    /// any earlier `hlt` belong to the old program and are dropped.
+    pub fn load(&mut self, vasm : Assembly) -> Result<(), ExecError> {
+        let program = decoder::decode(&vasm)?;
+        check_registers(&program, self.config.registers)?;
+        self.program = program;
+        self.contexts.clear();
+        self.calls.clear();
+        self.interrupts.clear();
+        self.halted = None;
+        self.index = 0;
+        Ok(())
+    }
+
+    /// Runs until the program finishes or an error is raised.
+    pub fn run(&mut self) -> Result<ExitReason, ExecError> {
         loop {
-            let code = self.tokens[self.index].clone();
-            
-            match code {
-                "label" => {
-                    self.index += 1;
-                },
-                "dmp" => if DUMP_VFS {
-                    println!(
-                        "!!! DUMPED !!!\n  Registers:\n    RAX: {}\n      *RAX: {}\n    RBX: {}\n      *RBX: {}\n    RCX: {}\n      *RCX: {}\n    RDX: {}\n      *RDX: {}\n  Memory:\n    {}\nVFS:\n    {:#?}",
-                    self.rax,
-                    self.mem[self.rax as usize],
-                    self.rbx,
-                    self.mem[self.rbx as usize],
-                    self.rcx,
-                    self.mem[self.rcx as usize],
-                    self.rdx,
-                    self.mem[self.rdx as usize],
-                    self.mem.into_iter()
-                        .enumerate()
-                        .map(|(index, byte)| { format!("{}: {}", index, byte) })
-                        .collect::<Vec<String>>()
-                        .join("\n    "),
-                    self.vfs.dmp());
-                } else {
-                    println!(
-                        "!!! DUMPED !!!\n  Registers:\n    RAX: {}\n      *RAX: {}\n    RBX: {}\n      *RBX: {}\n    RCX: {}\n      *RCX: {}\n    RDX: {}\n      *RDX: {}\n  Memory:\n    {}",
-                    self.rax,
-                    self.mem[self.rax as usize],
-                    self.rbx,
-                    self.mem[self.rbx as usize],
-                    self.rcx,
-                    self.mem[self.rcx as usize],
-                    self.rdx,
-                    self.mem[self.rdx as usize],
-                    self.mem.into_iter()
-                        .enumerate()
-                        .map(|(index, byte)| { format!("{}: {}", index, byte) })
-                        .collect::<Vec<String>>()
-                        .join("\n    "));
+            if let Some(reason) = self.step()? {
+                return Ok(reason);
+            }
+        }
+    }
 
+    /// Executes at most `n` instructions, returning `None` if the program is still running.
+    pub fn run_for(&mut self, n : usize) -> Result<Option<ExitReason>, ExecError> {
+        for _ in 0..n {
+            if let Some(reason) = self.step()? {
+                return Ok(Some(reason));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Executes a single instruction, returning `None` if the program is still running.
+    ///
+    /// The program finishes when execution reaches the index just past its last instruction, by
+    /// running off the end or jumping to a label there, and the programs suspended by `vrlx` have
+    /// finished too. `hlt` stops every program at once. Stepping a stopped machine returns the
+    /// same reason again without executing anything.
+    pub fn step(&mut self) -> Result<Option<ExitReason>, ExecError> {
+        if let Some(code) = self.halted {
+            return self.exit(ExitReason::Halted(code));
+        }
+        if self.unwind() {
+            return self.exit(ExitReason::Finished);
+        }
+
+        let instruction = self.program.instructions[self.index];
+        let result = if self.tracer.is_some() {
+            self.execute_traced(instruction)
+        } else {
+            self.execute(instruction)
+        };
+        for error in self.skipped.take() {
+            self.out(format!("{}\n", error).as_bytes())?;
+        }
+        if let Err(e) = result {
+            let unhandled = match self.deliver(&e) {
+                Ok(true) => None,
+                Ok(false) => Some(e),
+                // The handler itself is bad, e.g. its entry points past the end of the program.
+                Err(e) => Some(e)
+            };
+            if let Some(e) = unhandled {
+                // Keep the output and trace leading up to the error.
+                self.flush_output()?;
+                self.flush_trace()?;
+                return Err(e);
+            }
+        }
+        self.steps += 1;
+
+        if let Some(code) = self.halted {
+            return self.exit(ExitReason::Halted(code));
+        }
+        if self.unwind() {
+            return self.exit(ExitReason::Finished);
+        }
+        Ok(None)
+    }
+
+    // Hands an error to the guest's handler for its vector, false when there is none. Exceptions
+    // raised inside an exception handler always go to the host.
+    fn deliver(&mut self, error : &ExecError) -> Result<bool, ExecError> {
+        let handler = match self.exception_handler(&error.kind) {
+            Some(handler) => handler,
+            None => return Ok(false)
+        };
+        self.interrupt(handler, true)?;
+        Ok(true)
+    }
+
+    // Handler an error of this kind would be delivered to right now.
+    fn exception_handler(&self, kind : &ExecErrorKind) -> Option<usize> {
+        if self.interrupts.iter().any(|frame| frame.exception) {
+            return None;
+        }
+        kind.vector().and_then(|vector| self.handler(vector))
+    }
+
+    // Enters a handler, resuming after the current instruction on `iret`.
+    fn interrupt(&mut self, handler : usize, exception : bool) -> Result<(), ExecError> {
+        // Entries are guest-writable, so they get the same range check as jumps.
+        if handler > self.program.instructions.len() {
+            return Err(self.error(ExecErrorKind::JumpOutOfRange(handler)));
+        }
+        self.check_depth()?;
+        self.interrupts.push(InterruptFrame {
+            ret: self.index + 1,
+            flags: self.flags,
+            exception
+        });
+        self.index = handler;
+        Ok(())
+    }
+
+    // Instruction index of the handler installed for `vector`.
+    fn handler(&self, vector : u8) -> Option<usize> {
+        if vector as usize >= IVT_VECTORS {
+            return None;
+        }
+        let addr = self.config.ivt? + vector as usize * 4;
+        let entry = self.mem.get(addr..addr + 4)?;
+        match u32::from_le(bytemuck::pod_read_unaligned::<u32>(entry)) {
+            0 => None,
+            entry => Some(entry as usize - 1)
+        }
+    }
+
+    fn exit(&mut self, reason : ExitReason) -> Result<Option<ExitReason>, ExecError> {
+        self.flush_output()?;
+        self.flush_trace()?;
+        Ok(Some(reason))
+    }
+
+    /// Flushes guest output, which also happens whenever the machine stops.
+    pub fn flush_output(&mut self) -> Result<(), ExecError> {
+        self.output.flush().map_err(|e| self.error(ExecErrorKind::Io(e.to_string())))
+    }
+
+    fn out(&mut self, bytes : &[u8]) -> Result<(), ExecError> {
+        self.output.write(bytes).map_err(|e| self.error(ExecErrorKind::Io(e.to_string())))
+    }
+
+    fn flush_trace(&mut self) -> Result<(), ExecError> {
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.flush().map_err(|e| self.error(ExecErrorKind::Io(e.to_string())))?;
+        }
+        Ok(())
+    }
+
+    /// Logs every instruction executed from now on, or stops tracing with `None`.
+    pub fn set_tracer(&mut self, tracer : Option<Tracer>) {
+        if let Some(mut old) = std::mem::replace(&mut self.tracer, tracer) {
+            let _ = old.flush();
+        }
+    }
+
+    /// Services `sys` instructions, or with `None` makes every `sys` fail.
+    pub fn set_syscall_handler(&mut self, handler : Option<Box<dyn SyscallHandler>>) {
+        self.syscalls = handler;
+    }
+
+    /// Replaces where guest output goes, stdout by default. The old sink is flushed.
+    pub fn set_output(&mut self, output : Box<dyn OutputSink>) {
+        let _ = std::mem::replace(&mut self.output, output).flush();
+    }
+
+    /// Replaces where console input comes from, stdin by default.
+    pub fn set_input(&mut self, input : Box<dyn InputSource>) {
+        self.input = input;
+    }
+
+    fn execute_traced(&mut self, instruction : Instruction) -> Result<(), ExecError> {
+        let index = self.index;
+        let label = self.program.label_at(index).map(|(name, offset)| (name.to_owned(), offset));
+        let before = self.registers;
+        let flags = self.flags;
+
+        self.mem_writes = Some(Vec::new());
+        let result = self.execute(instruction);
+        let memory = self.mem_writes.take().unwrap_or_default();
+
+        let mut registers : Vec<(&'static str, u32, u32)> = self.register_file().iter()
+            .filter(|reg| before[reg.index()] != self.registers[reg.index()])
+            .map(|reg| (reg.name(), before[reg.index()], self.registers[reg.index()]))
+            .collect();
+        if flags != self.flags {
+            registers.push(("flags", flags, self.flags));
+        }
+
+        let record = TraceRecord {
+            step: self.steps,
+            index,
+            label,
+            instruction: instruction.to_string(),
+            registers,
+            memory,
+            error: result.as_ref().err().map(|e| e.kind.to_string())
+        };
+
+        if let Some(tracer) = self.tracer.as_mut() {
+            if let Err(e) = tracer.record(&record) {
+                return Err(self.error(ExecErrorKind::Io(e.to_string())));
+            }
+        }
+        result
+    }
+
+    // Returns to programs suspended by `vrlx` once the current one runs off its end.
+    // True when there is nothing left to run.
+    fn unwind(&mut self) -> bool {
+        while self.index >= self.program.instructions.len() {
+            match self.contexts.pop() {
+                // EXIT FORK CONTEXT
+                Some((program, index)) => {
+                    self.program = program;
+                    self.index = index + 1;
                 },
-                "panic" => panic!("Panic requested by instruction set at instr #{}", self.index),
-                "fault" => fault(format!("Fault requested by instr #{}.", self.index)),
-                "memset" => {
-                    self.index += 1;
-                    let addr_token = self.tokens[self.index].clone();
-                    let address = addr_token.parse::<usize>().expect("Invalid memory address in memset instruction. No memory write occurred.");
-        
-                    self.index += 1;
-                    let unparsed = self.tokens[self.index];
-                    let value = unparsed.parse::<u8>().expect("Invalid value in memset operation. No memory write occurred.");
-        
-                    if address < RESERVED_MIN_MEM_ADDR || address >= MEM_SIZE {
-                        fault(format!("Segmentation fault - Accessed memory out of bounds. Address: {}. Instr #{}", address, self.index));
-                        continue;
-                    }
-        
-                    self.mem[address] = value;
-                },
-                "mov" => {
-                    self.index += 1;
-                    let dest = self.tokens[self.index];
-                    self.index += 1;
-                    let src = self.tokens[self.index];
-                    let value = match src {
-                        "rax" => self.mem[self.rax as usize],
-                        "rbx" => self.mem[self.rbx as usize],
-                        "rcx" => self.mem[self.rcx as usize],
-                        "rdx" => self.mem[self.rdx as usize],
-                        _ => {
-                            if let Ok(addr) = src.parse::<usize>() {
-                                self.mem[addr]
-                            } else {
-                                panic!("Invalid token `{}` after mov instruction at instr #{}", src, self.index);
-                            }
-                        }
-                    };
-        
-                    match dest {
-                        "rax" => {
-                            self.rax = value;
-                        }
-                        "rbx" => {
-                            self.rbx = value;
-                        }
-                        "rcx" => {
-                            self.rcx = value;
-                        }
-                        "rdx" => {
-                            self.rdx = value;
-                        }
-                        _ => {
-                            if let Ok(addr) = dest.parse::<usize>() {
-                                if addr >= MEM_SIZE || addr < RESERVED_MIN_MEM_ADDR {
-                                    fault(format!("Segmentation fault - Accessed memory out of bounds. Address: {}. Instr #{}", addr, self.index));
-                                    continue;
-                                }
-        
-                                self.mem[addr] = value;
-                            } else {
-                                panic!("Invalid token `{}` after mov instruction at instr #{}", dest, self.index);
-                            }
-                        }
-                    }
+                None => return true
+            }
+        }
+        false
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+    pub fn set_index(&mut self, index : usize) {
+        self.index = index;
+    }
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+    /// The instruction the next `step` will execute.
+    pub fn current_instruction(&self) -> Option<&Instruction> {
+        self.program.instructions.get(self.index)
+    }
+    pub fn config(&self) -> &MachineConfig {
+        &self.config
+    }
+    /// The configured general registers followed by `rsp` and `rbp`.
+    pub fn register_file(&self) -> Vec<Register> {
+        Register::ALL.iter()
+            .copied()
+            .filter(|reg| !reg.is_general() || reg.index() < self.config.registers)
+            .collect()
+    }
+    /// Value of the register, or of the part of it `reg` names.
+    pub fn register(&self, reg : Register) -> u32 {
+        reg.get(self.registers[reg.index()])
+    }
+    pub fn set_register(&mut self, reg : Register, value : u32) {
+        reg.set(&mut self.registers[reg.index()], value);
+    }
+    /// Flags register, a combination of the `FLAG_*` bits.
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+    pub fn set_flags(&mut self, flags : u32) {
+        self.flags = flags & FLAG_MASK;
+    }
+    pub fn memory(&self) -> &[u8] {
+        &self.mem
+    }
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.mem
+    }
+    /// The host-side stack, empty when the stack lives in memory.
+    pub fn stack(&self) -> &[usize] {
+        &self.stack
+    }
+    /// Entries on the stack wherever it lives, bottom first.
+    pub fn stack_entries(&self) -> Vec<usize> {
+        let region = match self.config.stack_region {
+            Some(region) => region,
+            None => return self.stack.clone()
+        };
+
+        let sp = self.register(Register::RSP) as usize;
+        if sp < region.base || sp > region.top() {
+            return Vec::new();
+        }
+        (sp..region.top()).step_by(4).rev()
+            .filter_map(|addr| self.mem.get(addr..addr + 4))
+            .map(|word| u32::from_le(bytemuck::pod_read_unaligned::<u32>(word)) as usize)
+            .collect()
+    }
+    /// Return indices of the active calls, innermost last.
+    pub fn call_stack(&self) -> &[usize] {
+        &self.calls
+    }
+    pub fn call_depth(&self) -> usize {
+        self.calls.len()
+    }
+    pub fn vfs(&self) -> &VFS {
+        &self.vfs
+    }
+    pub fn vfs_mut(&mut self) -> &mut VFS {
+        &mut self.vfs
+    }
+
+    fn execute(&mut self, instruction : Instruction) -> Result<(), ExecError> {
+        match instruction {
+            Instruction::Dmp => {
+                let dump = self.dmp();
+                self.out(dump.as_bytes())?;
+            },
+            Instruction::Panic => return Err(self.error(ExecErrorKind::Panic)),
+            Instruction::Fault => self.fault(ExecErrorKind::Fault)?,
+            Instruction::Memset { address, value } => {
+                self.store_byte(address, value)?;
+            },
+            Instruction::Mov { dest, src } => {
+                let value = self.read(src)?;
+                self.write(dest, value)?;
+            },
+            Instruction::Arith { op, src1, src2, dest } => {
+                let val1 = self.read(src1)?;
+                let val2 = self.read(src2)?;
+
+                // Memory destinations take a byte.
+                let (value, flags) = arith(op, val1, val2, width(dest).unwrap_or(32))
+                    .ok_or_else(|| self.error(ExecErrorKind::DivideByZero))?;
+                self.write(dest, value)?;
+                self.flags = flags;
+            },
+            Instruction::Load { bytes, dest, src } => {
+                let value = self.load_word(self.address(src), bytes as usize)?;
+                self.set_register(dest, value);
+            },
+            Instruction::Store { bytes, dest, src } => {
+                let value = self.read(src)?;
+                self.store_word(self.address(dest), bytes as usize, value)?;
+            },
+            Instruction::Not { src, dest } => {
+                // Same flags as `xor` with all ones.
+                let (value, flags) = arith(ArithOp::Xor, self.read(src)?, u32::MAX, width(dest).unwrap_or(32))
+                    .ok_or_else(|| self.error(ExecErrorKind::InvalidOperand))?;
+                self.write(dest, value)?;
+                self.flags = flags;
+            },
+            Instruction::Goto(target) => {
+                self.index = self.target(target)?;
+                return Ok(());
+            },
+            Instruction::Cgt { reg, target } => {
+                if self.register(reg) > 0 {
+                    self.index = self.target(target)?;
+                    return Ok(());
                 }
-                "add" => {
-                    self.index += 1;
-                    let src1 = self.tokens[self.index];
-                    self.index += 1;
-                    let src2 = self.tokens[self.index];
-                    self.index += 1;
-                    let dest = self.tokens[self.index];
-        
-                    let val1 = match src1 {
-                        "rax" => self.rax,
-                        "rbx" => self.rbx,
-                        "rcx" => self.rcx,
-                        "rdx" => self.rdx,
-                        _ => panic!("Unrecognized register `{}` at instr #{}", src1, self.index)
-                    };
-                    let val2 = match src2 {
-                        "rax" => self.rax,
-                        "rbx" => self.rbx,
-                        "rcx" => self.rcx,
-                        "rdx" => self.rdx,
-                        _ => panic!("Unrecognized register `{}` at instr #{}", src2, self.index)
-                    };
-                    
-                    match dest {
-                        "rax" => {
-                            self.rax = val1 + val2;
-                        },
-                        "rbx" => {
-                            self.rbx = val1 + val2;
-                        },
-                        "rcx" => {
-                            self.rcx = val1 + val2;
-                        },
-                        "rdx" => {
-                            self.rdx = val1 + val2;
-                        },
-                        _ => panic!("Unrecognized register `{}` at instr #{}", dest, self.index)
-                    };
-                },
-                "sub" => {
-                    self.index += 1;
-                    let src1 = self.tokens[self.index];
-                    self.index += 1;
-                    let src2 = self.tokens[self.index];
-                    self.index += 1;
-                    let dest = self.tokens[self.index];
-        
-                    let val1 = match src1 {
-                        "rax" => self.rax,
-                        "rbx" => self.rbx,
-                        "rcx" => self.rcx,
-                        "rdx" => self.rdx,
-                        _ => panic!("Unrecognized register `{}` at instr #{}", src1, self.index)
-                    };
-                    let val2 = match src2 {
-                        "rax" => self.rax,
-                        "rbx" => self.rbx,
-                        "rcx" => self.rcx,
-                        "rdx" => self.rdx,
-                        _ => panic!("Unrecognized register `{}` at instr #{}", src2, self.index)
-                    };
-                    
-                    match dest {
-                        "rax" => {
-                            self.rax = val1 - val2;
-                        },
-                        "rbx" => {
-                            self.rbx = val1 - val2;
-                        },
-                        "rcx" => {
-                            self.rcx = val1 - val2;
-                        },
-                        "rdx" => {
-                            self.rdx = val1 - val2;
-                        },
-                        _ => panic!("Unrecognized register `{}` at instr #{}", dest, self.index)
-                    };
-                },
-                "mul" => {
-                    self.index += 1;
-                    let src1 = self.tokens[self.index];
-                    self.index += 1;
-                    let src2 = self.tokens[self.index];
-                    self.index += 1;
-                    let dest = self.tokens[self.index];
-        
-                    let val1 = match src1 {
-                        "rax" => self.rax,
-                        "rbx" => self.rbx,
-                        "rcx" => self.rcx,
-                        "rdx" => self.rdx,
-                        _ => panic!("Unrecognized register `{}` at instr #{}", src1, self.index)
-                    };
-                    let val2 = match src2 {
-                        "rax" => self.rax,
-                        "rbx" => self.rbx,
-                        "rcx" => self.rcx,
-                        "rdx" => self.rdx,
-                        _ => panic!("Unrecognized register `{}` at instr #{}", src2, self.index)
-                    };
-                    
-                    match dest {
-                        "rax" => {
-                            self.rax = val1 * val2;
-                        },
-                        "rbx" => {
-                            self.rbx = val1 * val2;
-                        },
-                        "rcx" => {
-                            self.rcx = val1 * val2;
-                        },
-                        "rdx" => {
-                            self.rdx = val1 * val2;
-                        },
-                        _ => panic!("Unrecognized register `{}` at instr #{}", dest, self.index)
-                    };
-                },
-                "div" => {
-                    self.index += 1;
-                    let src1 = self.tokens[self.index];
-                    self.index += 1;
-                    let src2 = self.tokens[self.index];
-                    self.index += 1;
-                    let dest = self.tokens[self.index];
-        
-                    let val1 = match src1 {
-                        "rax" => self.rax,
-                        "rbx" => self.rbx,
-                        "rcx" => self.rcx,
-                        "rdx" => self.rdx,
-                        _ => panic!("Unrecognized register `{}` at instr #{}", src1, self.index)
-                    };
-                    let val2 = match src2 {
-                        "rax" => self.rax,
-                        "rbx" => self.rbx,
-                        "rcx" => self.rcx,
-                        "rdx" => self.rdx,
-                        _ => panic!("Unrecognized register `{}` at instr #{}", src2, self.index)
-                    };
-                    
-                    match dest {
-                        "rax" => {
-                            self.rax = val1 / val2;
-                        },
-                        "rbx" => {
-                            self.rbx = val1 / val2;
-                        },
-                        "rcx" => {
-                            self.rcx = val1 / val2;
-                        },
-                        "rdx" => {
-                            self.rdx = val1 / val2;
-                        },
-                        _ => panic!("Unrecognized register `{}` at instr #{}", dest, self.index)
-                    };
-                },
-                "goto" => {
-                    self.index += 1;
-                    let position = match self.tokens[self.index] {
-                        "rax" => self.rax as usize,
-                        "rbx" => self.rbx as usize,
-                        "rcx" => self.rcx as usize,
-                        "rdx" => self.rdx as usize,
-                        _ => {
-                            if let Some(position) = self.label_table.get(self.tokens[self.index]) {
-                                *position
-                            } else {
-                                panic!("Invalid label {} at instr #{}.", self.tokens[self.index], self.index);
-                            }
-                        }
-                    };
+            },
+            Instruction::Cmp { lhs, rhs } => {
+                self.compare(lhs, rhs)?;
+            },
+            Instruction::Jump { cond, target } => {
+                if holds(cond, self.flags) {
+                    self.index = self.target(target)?;
+                    return Ok(());
+                }
+            },
+            Instruction::CallIf { cond, target } => {
+                if holds(cond, self.flags) {
+                    self.call(target)?;
+                    return Ok(());
+                }
+            },
+            Instruction::Compare { op, dest, lhs, rhs } => {
+                let (lhs, rhs) = self.compare(lhs, rhs)?;
 
-                    self.index = position;
-                    continue;
-                },
-                "cgt" => {
-                    self.index += 1;
-                    let reg = self.tokens[self.index];
-                    self.index += 1;
-
-                    let condition = match reg {
-                        "rax" => self.rax > 0,
-                        "rbx" => self.rbx > 0,
-                        "rcx" => self.rcx > 0,
-                        "rdx" => self.rdx > 0,
-                        _ => panic!("Unrecognized register {} at instr #{}", reg, self.index)
-                    };
-
-                    if condition {
-                        if let Some(position) = self.label_table.get(self.tokens[self.index]) {
-                            self.index = position.clone();
-                            continue;
-                        } else {
-                            panic!("Invalid label {} at instr #{}.", self.tokens[self.index], self.index);
-                        }
-                    }
-                },
-                "grt" => {
-                    self.index += 1;
-                    let dest = self.tokens[self.index];
-                    self.index += 1;
-                    let lhs = match self.tokens[self.index] {
-                        "rax" => self.rax,
-                        "rbx" => self.rbx,
-                        "rcx" => self.rcx,
-                        "rdx" => self.rdx,
-                        _ => panic!("Unrecognized register `{}` at instr #{}", self.tokens[self.index], self.index)
-                    };
-                    self.index += 1;
-                    let rhs = match self.tokens[self.index] {
-                        "rax" => self.rax,
-                        "rbx" => self.rbx,
-                        "rcx" => self.rcx,
-                        "rdx" => self.rdx,
-                        _ => panic!("Unrecognized register `{}` at instr #{}", self.tokens[self.index], self.index)
-                    };
-
-                    let value = if rhs > lhs { 1 } else { 0 };
-
-                    match dest {
-                        "rax" => { self.rax = value },
-                        "rbx" => { self.rbx = value },
-                        "rcx" => { self.rcx = value },
-                        "rdx" => { self.rdx = value },
-                        _ => panic!("Unrecognized register `{}` at instr #{}", self.tokens[self.index], self.index)
-                    }
-                },
-                "lt" => {
-                    self.index += 1;
-                    let dest = self.tokens[self.index];
-                    self.index += 1;
-                    let lhs = match self.tokens[self.index] {
-                        "rax" => self.rax,
-                        "rbx" => self.rbx,
-                        "rcx" => self.rcx,
-                        "rdx" => self.rdx,
-                        _ => panic!("Unrecognized register `{}` at instr #{}", self.tokens[self.index], self.index)
-                    };
-                    self.index += 1;
-                    let rhs = match self.tokens[self.index] {
-                        "rax" => self.rax,
-                        "rbx" => self.rbx,
-                        "rcx" => self.rcx,
-                        "rdx" => self.rdx,
-                        _ => panic!("Unrecognized register `{}` at instr #{}", self.tokens[self.index], self.index)
-                    };
-
-                    let value = if rhs < lhs { 1 } else { 0 };
-
-                    match dest {
-                        "rax" => { self.rax = value },
-                        "rbx" => { self.rbx = value },
-                        "rcx" => { self.rcx = value },
-                        "rdx" => { self.rdx = value },
-                        _ => panic!("Unrecognized register `{}` at instr #{}", self.tokens[self.index], self.index)
-                    }
-                },
-                "eq" => {
-                    self.index += 1;
-                    let dest = self.tokens[self.index];
-                    self.index += 1;
-                    let lhs = match self.tokens[self.index] {
-                        "rax" => self.rax,
-                        "rbx" => self.rbx,
-                        "rcx" => self.rcx,
-                        "rdx" => self.rdx,
-                        "*rax" => self.mem[self.rax as usize],
-                        "*rbx" => self.mem[self.rbx as usize],
-                        "*rcx" => self.mem[self.rcx as usize],
-                        "*rdx" => self.mem[self.rdx as usize],
-                        _ => panic!("Unrecognized register `{}` at instr #{}", self.tokens[self.index], self.index)
-                    };
-                    self.index += 1;
-                    let rhs = match self.tokens[self.index] {
-                        "rax" => self.rax,
-                        "rbx" => self.rbx,
-                        "rcx" => self.rcx,
-                        "rdx" => self.rdx,
-                        "*rax" => self.mem[self.rax as usize],
-                        "*rbx" => self.mem[self.rbx as usize],
-                        "*rcx" => self.mem[self.rcx as usize],
-                        "*rdx" => self.mem[self.rdx as usize],
-                        _ => panic!("Unrecognized register `{}` at instr #{}", self.tokens[self.index], self.index)
-                    };
-
-                    let value = if rhs == lhs { 1 } else { 0 };
-
-                    match dest {
-                        "rax" => { self.rax = value },
-                        "rbx" => { self.rbx = value },
-                        "rcx" => { self.rcx = value },
-                        "rdx" => { self.rdx = value },
-                        "*rax" => { self.mem[self.rax as usize] = value },
-                        "*rbx" => { self.mem[self.rbx as usize] = value },
-                        "*rcx" => { self.mem[self.rcx as usize] = value },
-                        "*rdx" => { self.mem[self.rdx as usize] = value },
-                        _ => panic!("Unrecognized register `{}` at instr #{}", self.tokens[self.index], self.index)
-                    }
-                },
-                "outstr" => {
-                    self.index += 1;
-                    let byte = match self.tokens[self.index] {
-                        "rax" => self.rax,
-                        "rbx" => self.rbx,
-                        "rcx" => self.rcx,
-                        "rdx" => self.rdx,
-                        "*rax" => self.mem[self.rax as usize],
-                        "*rbx" => self.mem[self.rbx as usize],
-                        "*rcx" => self.mem[self.rcx as usize],
-                        "*rdx" => self.mem[self.rdx as usize],
-                        _ => {
-                            if let Ok(val) = self.tokens[self.index].parse::<u8>() {
-                                val
-                            } else {
-                                panic!("Invalid out byte.");
-                            }
-                        }
-                    };
+                let condition = match op {
+                    CompareOp::Greater => rhs > lhs,
+                    CompareOp::Less => rhs < lhs,
+                    CompareOp::Equal => rhs == lhs
+                };
 
-                    print!("{}", String::from_utf8_lossy(&[byte]));
-                },
-                "outbyte" => {
-                    self.index += 1;
-                    let byte = match self.tokens[self.index] {
-                        "rax" => self.mem[self.rax as usize],
-                        "rbx" => self.mem[self.rbx as usize],
-                        "rcx" => self.mem[self.rcx as usize],
-                        "rdx" => self.mem[self.rdx as usize],
-                        _ => {
-                            if let Ok(val) = self.tokens[self.index].parse::<u8>() {
-                                val
-                            } else {
-                                panic!("Invalid out byte.");
-                            }
-                        }
-                    };
+                self.write(dest, if condition { 1 } else { 0 })?;
+            },
+            Instruction::OutStr(src) => {
+                let byte = self.read(src)? as u8;
+                self.out(&[byte])?;
+            },
+            Instruction::OutByte(src) => {
+                let byte = self.read(src)? as u8;
+                self.out(byte.to_string().as_bytes())?;
+            },
+            Instruction::Vfsr { pointer, file } => {
+                let start_ptr = self.read(pointer)? as usize;
 
-                    print!("{}", &byte);
-                },
-                "vfsr" => {
-                    self.index += 1;
-                    let start_ptr = match self.tokens[self.index] {
-                        "rax" => self.rax as usize,
-                        "rbx" => self.rbx as usize,
-                        "rcx" => self.rcx as usize,
-                        "rdx" => self.rdx as usize,
-                        _ => self.tokens[self.index].parse::<usize>().expect("Failed to parse pointer literal.")
-                    };
-
-                    // MEMORY ADDRESS IS A POINTER TO A POINTER NOT A DIRECT POINTER
-                    // THIS IS BECAUSE REGISTERS CAN ONLY HOLD A u8 NOT A usize
-
-                    self.index += 1;
-                    let identifier = self.tokens[self.index].parse::<u8>().expect("Failed to parse file identifier.");
-                    let file = self.vfs.read_file(identifier).expect("Failed to read file.");
-
-                    if file.contents.len() + start_ptr >= MEM_SIZE {
-                        fault("SEGMENTATION FAULT - FAILED TO READ FILE INTO INVALID MEMORY".to_owned());
-                    }
-                    
-                    for (ptr, value) in file.contents.iter().enumerate() {
-                        self.mem[start_ptr + ptr] = *value;
+                let contents = match self.vfs.read_file(file) {
+                    Ok(file) => file.contents.clone(),
+                    Err(e) => return Err(self.error(ExecErrorKind::VfsError(e)))
+                };
+
+                if start_ptr < self.config.reserved {
+                    self.fault(ExecErrorKind::Segfault(start_ptr))?;
+                } else if start_ptr + contents.len() > self.config.mem_size {
+                    self.fault(ExecErrorKind::Segfault(start_ptr.max(self.config.mem_size)))?;
+                } else {
+                    for (offset, value) in contents.into_iter().enumerate() {
+                        self.store_byte(start_ptr + offset, value)?;
                     }
-                },
-                "//" => {
-                    self.index += 1;
-                    loop {
-                        self.index += 1;
-                        if self.index >= self.tokens.len() {
-                            panic!("EOF after comment without closing.");
-                        }
+                }
+            },
+            Instruction::Vrlx(fid) => {
+                let step_program = self.load_program(fid)?;
+
+                // CONTEXT SWITCH //
+
+                let tmp_program = std::mem::replace(&mut self.program, step_program);
+                self.contexts.push((tmp_program, self.index));
+
+                self.index = 0;
+                return Ok(());
+            },
+            Instruction::Inv(reg) => {
+                let value = if self.register(reg) > 0 { 0 } else { 1 };
+                self.set_register(reg, value);
+            },
+            Instruction::Push(src) => {
+                let value = self.read(src)?;
+                self.push(value as usize)?;
+            },
+            Instruction::Pop(dest) => {
+                let value = self.pop()?;
+                self.write(dest, value as u32)?;
+            },
+            Instruction::Call(target) => {
+                self.call(target)?;
+                return Ok(());
+            },
+            Instruction::Esr => {
+                let e = self.calls.pop().ok_or_else(|| self.error(ExecErrorKind::ReturnWithoutCall))?;
 
-                        if self.tokens[self.index] == "//" {
+                self.index = e;
+                return Ok(());
+            },
+            Instruction::Hlt(code) => {
+                self.halted = Some(self.read(code)?);
+                return Ok(());
+            },
+            Instruction::Int(vector) => {
+                let handler = self.handler(vector).ok_or_else(|| self.error(ExecErrorKind::UnhandledInterrupt(vector)))?;
+                self.interrupt(handler, false)?;
+                return Ok(());
+            },
+            Instruction::Iret => {
+                let frame = self.interrupts.pop().ok_or_else(|| self.error(ExecErrorKind::ReturnWithoutInterrupt))?;
+                self.index = frame.ret;
+                self.flags = frame.flags;
+                return Ok(());
+            },
+            Instruction::Ivt { vector, target } => {
+                let addr = match self.config.ivt {
+                    Some(base) if (vector as usize) < IVT_VECTORS => base + vector as usize * 4,
+                    _ => return Err(self.error(ExecErrorKind::InvalidOperand))
+                };
+                if addr + 4 > self.mem.len() {
+                    return Err(self.error(ExecErrorKind::Segfault(addr)));
+                }
+                // Writes past the reserved check, this is how the guest installs handlers.
+                let entry = self.target(target)? as u32 + 1;
+                for (offset, byte) in entry.to_le_bytes().into_iter().enumerate() {
+                    self.write_mem(addr + offset, byte);
+                }
+            },
+            Instruction::Sys(number) => {
+                let args = [self.register(Register::RBX), self.register(Register::RCX), self.register(Register::RDX)];
+                let result = match self.syscalls.as_mut() {
+                    Some(handler) => handler.syscall(number, args, &mut self.mem),
+                    None => Err(SyscallError::unknown(number))
+                };
+                let value = result.map_err(|e| self.error(ExecErrorKind::Syscall(e)))?;
+                self.set_register(Register::RAX, value);
+            },
+            Instruction::InByte(dest) => {
+                let byte = self.input.read_byte().map_err(|e| self.error(ExecErrorKind::Io(e.to_string())))?;
+                self.write(dest, byte.map_or(u32::MAX, |b| b as u32))?;
+                self.set_end_of_input(byte.is_none());
+            },
+            Instruction::Poll(dest) => {
+                let ready = self.input.ready().map_err(|e| self.error(ExecErrorKind::Io(e.to_string())))?;
+                self.write(dest, ready as u32)?;
+            },
+            Instruction::ReadLine { buf, max, dest } => {
+                let start = self.address(buf);
+                let max = self.read(max)? as usize;
+
+                let mut len = 0;
+                let mut eof = false;
+                while len < max {
+                    match self.input.read_byte().map_err(|e| self.error(ExecErrorKind::Io(e.to_string())))? {
+                        Some(b'\n') => break,
+                        Some(byte) => {
+                            self.store_byte(start + len, byte)?;
+                            len += 1;
+                        },
+                        None => {
+                            eof = true;
                             break;
                         }
                     }
-                },
-                "vrlx" => {
-                    self.index += 1;
-                    let fid = self.tokens[self.index].parse::<u8>().expect("Failed to parse fid in vrlx op.");
+                }
+                self.write(dest, if eof && len == 0 { u32::MAX } else { len as u32 })?;
+                self.set_end_of_input(eof && len == 0);
+            },
+            Instruction::Rdf(reg) => self.set_register(reg, self.flags),
+            Instruction::Pushf => self.push(self.flags as usize)?,
+            Instruction::Popf => {
+                let flags = self.pop()?;
+                self.set_flags(flags as u32);
+            }
+        }
 
-                    let step_read = self.vfs.read_file(fid).expect("Failed to read vraw in vrlx op.");
+        self.index += 1;
+        Ok(())
+    }
 
-                    let l : &'static str = Box::leak(String::from_utf8_lossy(&step_read.contents).to_string().into_boxed_str());
+    fn load_program(&self, fid : u8) -> Result<Program, ExecError> {
+        let step_read = self.vfs.read_file(fid).map_err(|e| self.error(ExecErrorKind::VfsError(e)))?;
+        let step_load = tokenizer::load_image(&step_read.contents).map_err(|_| self.error(ExecErrorKind::InvalidImage))?;
 
-                    let step_load = tokenizer::parse_asm(l);
+        let program = decoder::decode(&step_load)?;
+        check_registers(&program, self.config.registers)?;
+        Ok(program)
+    }
 
-                    // CONTEXT SWITCH //
+    fn error(&self, kind : ExecErrorKind) -> ExecError {
+        ExecError {
+            kind,
+            index: self.index,
+            token: self.program.instructions.get(self.index).map(|i| i.to_string()).unwrap_or_default()
+        }
+    }
 
-                    let tmp_index = self.index.clone();
-                    let tmp_tokens = self.tokens.clone();
-                    let tmp_lt = self.label_table.clone();
+    // Carry tells end of input apart from a 0xFF byte, which read the same into byte-sized places.
+    fn set_end_of_input(&mut self, end : bool) {
+        if end {
+            self.flags |= FLAG_CARRY;
+        } else {
+            self.flags &= !FLAG_CARRY;
+        }
+    }
 
-                    self.index = 0;
-                    self.tokens = step_load.tokens;
-                    self.label_table = step_load.label_table;
-                    self.run();
+    // Recoverable faults only abort execution when continue_after_fault is off, or when the guest
+    // has a handler to deliver them to.
+    fn fault(&self, kind : ExecErrorKind) -> Result<(), ExecError> {
+        let handled = self.exception_handler(&kind).is_some();
+        let error = self.error(kind);
+        if self.config.continue_after_fault && !handled {
+            self.skipped.borrow_mut().push(error);
+            Ok(())
+        } else {
+            Err(error)
+        }
+    }
 
-                    self.index = tmp_index;
-                    self.tokens = tmp_tokens;
-                    self.label_table = tmp_lt;
-                    
-                    // EXIT FORK CONTEXT
-                },
-                "inv" => {
-                    self.index += 1;
-
-                    match self.tokens[self.index] {
-                        "rax" => if self.rax > 0 { self.rax = 0; } else { self.rax = 1; },
-                        "rbx" => if self.rbx > 0 { self.rbx = 0; } else { self.rbx = 1; },
-                        "rcx" => if self.rcx > 0 { self.rcx = 0; } else { self.rcx = 1; },
-                        "rdx" => if self.rdx > 0 { self.rdx = 0; } else { self.rdx = 1; },
-                        _ => panic!("Unrecognized token after invert instruction.")
-                    }
-                },
-                "push" => {
-                    self.index += 1;
-                    let next = self.tokens[self.index];
-                    self.stack.push(match next {
-                        "rax" => self.rax as usize,
-                        "*rax" => self.mem[self.rax as usize] as usize,
-                        "rbx" => self.rbx as usize,
-                        "*rbx" => self.mem[self.rbx as usize] as usize,
-                        "rcx" => self.rcx as usize,
-                        "*rcx" => self.mem[self.rcx as usize] as usize,
-                        "rdx" => self.rdx as usize,
-                        "*rdx" => self.mem[self.rdx as usize] as usize,
-                        _ => {
-                            let x = next.chars().next().unwrap_or(' ');
-                            let full = next.split_at(1).1;
-                            match x {
-                                '#' => full.parse::<usize>().expect("Failed to parse numeric literal after push instr."),
-                                '*' => self.mem[full.parse::<usize>().expect("Failed to parse pointer.")] as usize,
-                                _ => panic!("Unrecognized literal after push instr.")
-                            }
-                        }
-                    });
-                },
-                "pop" => {
-                    let usize = self.stack.pop().expect("Failed to pop element off stack - no elements remaining to pop.");
-
-                    self.index += 1;
-                    let token = self.tokens[self.index];
-
-                    match token {
-                        "rax" => self.rax = usize as u8,
-                        "*rax" => self.mem[self.rax as usize] = usize as u8,
-                        "rbx" => self.rbx = usize as u8,
-                        "*rbx" => self.mem[self.rbx as usize] = usize as u8,
-                        "rcx" => self.rcx = usize as u8,
-                        "*racx" => self.mem[self.rcx as usize] = usize as u8,
-                        "rdx" => self.rdx = usize as u8,
-                        "*rdx" => self.mem[self.rdx as usize] = usize as u8,
-                        _ => {
-                            let first = token.chars().next().unwrap_or(' ');
-                            let full = token.split_at(1).1;
-
-                            match first {
-                                '*' => {
-                                    let addr = full.parse::<usize>().expect("Failed to parse raw pointer.");
-
-                                    self.mem[addr] = usize as u8;
-                                },
-                                _ => panic!("Unknown identifier.")
-                            }
-                        }
-                    }
-                },
-                "call" => {
-                    self.index += 1;
-                    let t = self.tokens[self.index];
+    fn check_depth(&self) -> Result<(), ExecError> {
+        if let Some(limit) = self.config.call_limit {
+            if self.calls.len() + self.interrupts.len() >= limit {
+                return Err(self.error(ExecErrorKind::CallStackOverflow));
+            }
+        }
+        Ok(())
+    }
 
-                    if !self.label_table.contains_key(t) {
-                        panic!("Called undefined subroutine {}!", t);
-                    }
+    fn call(&mut self, target : Operand) -> Result<(), ExecError> {
+        self.check_depth()?;
+        let target = self.target(target)?;
+        self.calls.push(self.index + 1);
+        self.index = target;
+        Ok(())
+    }
 
-                    self.stack.push(self.index + 1);
-                    
-                    self.index = *self.label_table.get(t).unwrap();
-                    continue;
-                }
-                "esr" => {
-                    let e = self.stack.pop().expect("Failed to exit an undefined subroutine!");
+    fn push(&mut self, value : usize) -> Result<(), ExecError> {
+        if let Some(region) = self.config.stack_region {
+            let sp = self.register(Register::RSP) as usize;
+            if sp > region.top() || sp < region.base + 4 {
+                return Err(self.error(ExecErrorKind::StackOverflow));
+            }
+            self.store_word(sp - 4, 4, value as u32)?;
+            self.set_register(Register::RSP, (sp - 4) as u32);
+            return Ok(());
+        }
 
-                    self.index = e;
-                    continue;
-                }
-                _ => fault(format!("Unrecognized instruction `{}` at instr #{}", code, self.index))
+        if let Some(limit) = self.config.stack_limit {
+            if self.stack.len() >= limit {
+                return Err(self.error(ExecErrorKind::StackOverflow));
             }
+        }
+
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<usize, ExecError> {
+        if let Some(region) = self.config.stack_region {
+            let sp = self.register(Register::RSP) as usize;
+            if sp < region.base || sp + 4 > region.top() {
+                return Err(self.error(ExecErrorKind::StackUnderflow));
+            }
+            let value = self.load_word(sp, 4)?;
+            self.set_register(Register::RSP, (sp + 4) as u32);
+            return Ok(value as usize);
+        }
+
+        self.stack.pop().ok_or_else(|| self.error(ExecErrorKind::StackUnderflow))
+    }
+
+    // Reads both sides and sets the flags as for `lhs - rhs`, at the width of the first operand that
+    // has one and 32 bits for two immediates.
+    fn compare(&mut self, lhs : Operand, rhs : Operand) -> Result<(u32, u32), ExecError> {
+        let bits = width(lhs).or_else(|| width(rhs)).unwrap_or(32);
+        let (lhs, rhs) = (self.read(lhs)?, self.read(rhs)?);
+        self.flags = arith(ArithOp::Sub, lhs, rhs, bits).map_or(0, |(_, flags)| flags);
+        Ok((lhs, rhs))
+    }
+
+    fn read(&self, operand : Operand) -> Result<u32, ExecError> {
+        Ok(match operand {
+            Operand::Register(reg) => self.register(reg),
+            Operand::Immediate(value) => value,
+            Operand::Memory(mem) => self.load_byte(self.address(mem))? as u32,
+            Operand::Label(..) => return Err(self.error(ExecErrorKind::InvalidOperand))
+        })
+    }
+
+    // Instruction index a jump goes to, from a label or a register holding an index.
+    // Jumping to the end of the program finishes it, anywhere further is an error.
+    fn target(&self, operand : Operand) -> Result<usize, ExecError> {
+        let target = match operand {
+            Operand::Label(_, position) => position,
+            _ => self.read(operand)? as usize
+        };
+        if target > self.program.instructions.len() {
+            return Err(self.error(ExecErrorKind::JumpOutOfRange(target)));
+        }
+        Ok(target)
+    }
+
+    fn address(&self, mem : MemRef) -> usize {
+        let base = mem.base.map_or(0, |reg| self.register(reg));
+        let index = mem.index.map_or(0, |reg| self.register(reg));
+        base.wrapping_add(index).wrapping_add(mem.offset) as usize
+    }
+
+    // Registers take the value truncated to their width, memory takes the low byte.
+    fn write(&mut self, operand : Operand, value : u32) -> Result<(), ExecError> {
+        match operand {
+            Operand::Register(reg) => self.set_register(reg, value),
+            Operand::Memory(mem) => self.store_byte(self.address(mem), value as u8)?,
+            Operand::Immediate(_) | Operand::Label(..) => return Err(self.error(ExecErrorKind::InvalidOperand))
+        }
+        Ok(())
+    }
+
+    fn load_byte(&self, addr : usize) -> Result<u8, ExecError> {
+        if !(self.config.reserved..self.config.mem_size).contains(&addr) {
+            self.fault(ExecErrorKind::Segfault(addr))?;
+            return Ok(0);
+        }
 
-            self.index += 1;
-            if self.index >= len { break; }
+        Ok(self.mem[addr])
+    }
+
+    fn store_byte(&mut self, addr : usize, value : u8) -> Result<(), ExecError> {
+        if !(self.config.reserved..self.config.mem_size).contains(&addr) {
+            return self.fault(ExecErrorKind::Segfault(addr));
+        }
+
+        self.write_mem(addr, value);
+        Ok(())
+    }
+
+    // Unchecked write, recorded while tracing.
+    fn write_mem(&mut self, addr : usize, value : u8) {
+        if let Some(writes) = self.mem_writes.as_mut() {
+            if self.mem[addr] != value {
+                writes.push((addr, self.mem[addr], value));
+            }
+        }
+        self.mem[addr] = value;
+    }
+
+    // Checks an access of `bytes` bytes at `addr` is in bounds and aligned. False when it faulted
+    // but execution continues, in which case the access is skipped.
+    fn check_word(&self, addr : usize, bytes : usize) -> Result<bool, ExecError> {
+        if addr < self.config.reserved || addr.saturating_add(bytes) > self.config.mem_size {
+            self.fault(ExecErrorKind::Segfault(addr))?;
+            return Ok(false);
+        }
+        if !addr.is_multiple_of(bytes) {
+            self.fault(ExecErrorKind::Misaligned(addr))?;
+            return Ok(false);
         }
+        Ok(true)
+    }
+
+    fn load_word(&self, addr : usize, bytes : usize) -> Result<u32, ExecError> {
+        if !self.check_word(addr, bytes)? {
+            return Ok(0);
+        }
+
+        let slice = &self.mem[addr..addr + bytes];
+        Ok(match bytes {
+            2 => u16::from_le(bytemuck::pod_read_unaligned::<u16>(slice)) as u32,
+            _ => u32::from_le(bytemuck::pod_read_unaligned::<u32>(slice))
+        })
+    }
+
+    fn store_word(&mut self, addr : usize, bytes : usize, value : u32) -> Result<(), ExecError> {
+        if !self.check_word(addr, bytes)? {
+            return Ok(());
+        }
+
+        // Byte at a time so traced writes are recorded.
+        for (offset, byte) in value.to_le_bytes().into_iter().take(bytes).enumerate() {
+            self.store_byte(addr + offset, byte)?;
+        }
+        Ok(())
+    }
+
+    fn dmp(&self) -> String {
+        let registers = self.register_file().iter()
+            .map(|reg| {
+                let value = self.registers[reg.index()];
+                let name = reg.name().to_uppercase();
+                let pointee = match self.mem.get(value as usize) {
+                    Some(byte) => byte.to_string(),
+                    None => "-".to_owned()
+                };
+                format!("    {}: {}\n      *{}: {}", name, value, name, pointee)
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let registers = format!("{}\n    FLAGS: {}", registers, format_flags(self.flags));
+
+        if self.config.dump == DumpVerbosity::Registers {
+            return format!("!!! DUMPED !!!\n  Registers:\n{}\n", registers);
+        }
+
+        let memory = self.mem.iter()
+            .enumerate()
+            .map(|(index, byte)| { format!("{}: {}", index, byte) })
+            .collect::<Vec<String>>()
+            .join("\n    ");
+
+        if self.config.dump == DumpVerbosity::Full {
+            format!("!!! DUMPED !!!\n  Registers:\n{}\n  Memory:\n    {}\nVFS:\n    {:#?}\n", registers, memory, self.vfs.dmp())
+        } else {
+            format!("!!! DUMPED !!!\n  Registers:\n{}\n  Memory:\n    {}\n", registers, memory)
+        }
+    }
+}
+
+// Wrapping result of `lhs op rhs` at `bits` wide, with the flags it sets. Operands are truncated to
+// the same width first. `None` on division by zero.
+//
+// Bitwise ops clear carry and overflow. Shifts and rotates leave the last bit moved out (or, for
+// rotates, moved around) in carry and clear overflow; rotate counts wrap at `bits`.
+fn arith(op : ArithOp, lhs : u32, rhs : u32, bits : u32) -> Option<(u32, u32)> {
+    let mask = u64::MAX >> (64 - bits);
+    let sign = 1u64 << (bits - 1);
+    let (a, b) = (lhs as u64 & mask, rhs as u64 & mask);
+    // Sign extended to i64, products of two 32-bit values always fit.
+    let signed = |v : u64| if v & sign != 0 { (v | !mask) as i64 } else { v as i64 };
+
+    let (full, carry, overflow) = match op {
+        ArithOp::Add => {
+            let full = a + b;
+            (full, full > mask, (a ^ full) & (b ^ full) & sign != 0)
+        },
+        ArithOp::Sub => {
+            let full = a.wrapping_sub(b);
+            (full, a < b, (a ^ b) & (a ^ full) & sign != 0)
+        },
+        ArithOp::Mul => {
+            let product = signed(a) * signed(b);
+            (a * b, a * b > mask, product != signed(product as u64 & mask))
+        },
+        ArithOp::Div => (a.checked_div(b)?, false, false),
+        ArithOp::And => (a & b, false, false),
+        ArithOp::Or => (a | b, false, false),
+        ArithOp::Xor => (a ^ b, false, false),
+        ArithOp::Shl => {
+            let full = if b >= bits as u64 { 0 } else { a << b };
+            (full & mask, b > 0 && b <= bits as u64 && (a >> (bits as u64 - b)) & 1 != 0, false)
+        },
+        ArithOp::Shr => {
+            let full = if b >= bits as u64 { 0 } else { a >> b };
+            (full, b > 0 && b <= bits as u64 && (a >> (b - 1)) & 1 != 0, false)
+        },
+        ArithOp::Sar => {
+            let full = (signed(a) >> b.min(63)) as u64;
+            (full & mask, b > 0 && (signed(a) >> (b - 1).min(63)) & 1 != 0, false)
+        },
+        ArithOp::Rol => {
+            let n = b % bits as u64;
+            let full = ((a << n) | (a >> (bits as u64 - n))) & mask;
+            (full, b > 0 && full & 1 != 0, false)
+        },
+        ArithOp::Ror => {
+            let n = b % bits as u64;
+            let full = ((a >> n) | (a << (bits as u64 - n))) & mask;
+            (full, b > 0 && full & sign != 0, false)
+        }
+    };
+
+    let value = full & mask;
+    let mut flags = 0;
+    if value == 0 { flags |= FLAG_ZERO; }
+    if carry { flags |= FLAG_CARRY; }
+    if value & sign != 0 { flags |= FLAG_SIGN; }
+    if overflow { flags |= FLAG_OVERFLOW; }
+    Some((value as u32, flags))
+}
+
+// The whole region must be writable, `rsp` has to be able to hold its top, and both ends must be
+// word aligned since `push` and `pop` move whole words.
+fn check_stack_region(region : StackRegion, config : &MachineConfig) -> Result<(), ExecError> {
+    let fits = region.base.checked_add(region.size)
+        .is_some_and(|top| region.base >= config.reserved && top <= config.mem_size && top <= u32::MAX as usize);
+    let aligned = region.base.is_multiple_of(4) && region.size.is_multiple_of(4);
+    if fits && aligned {
+        return Ok(());
+    }
+    Err(ExecError {
+        kind: ExecErrorKind::InvalidStackRegion(region),
+        index: 0,
+        token: format!("{}:{}", region.base, region.size)
+    })
+}
+
+// Rejects programs naming general registers past the configured count.
+fn check_registers(program : &Program, count : usize) -> Result<(), ExecError> {
+    for (index, instruction) in program.instructions.iter().enumerate() {
+        if let Some(reg) = instruction.registers().into_iter().find(|reg| reg.is_general() && reg.index() >= count) {
+            return Err(ExecError {
+                kind: ExecErrorKind::InvalidOperand,
+                index,
+                token: format!("{} ({} registers configured)", reg.name(), count)
+            });
+        }
+    }
+    Ok(())
+}
+
+// Width an operand is read at, `None` for immediates.
+fn width(operand : Operand) -> Option<u32> {
+    match operand {
+        Operand::Register(reg) => Some(reg.bits()),
+        Operand::Memory(_) => Some(8),
+        Operand::Immediate(_) | Operand::Label(..) => None
+    }
+}
+
+fn holds(cond : Condition, flags : u32) -> bool {
+    let zero = flags & FLAG_ZERO != 0;
+    let carry = flags & FLAG_CARRY != 0;
+    // Signed less-than after `cmp`.
+    let less = (flags & FLAG_SIGN != 0) != (flags & FLAG_OVERFLOW != 0);
+
+    match cond {
+        Condition::Zero | Condition::Equal => zero,
+        Condition::NotZero | Condition::NotEqual => !zero,
+        Condition::Less => less,
+        Condition::LessEqual => less || zero,
+        Condition::Greater => !less && !zero,
+        Condition::GreaterEqual => !less,
+        Condition::Below => carry,
+        Condition::BelowEqual => carry || zero,
+        Condition::Above => !carry && !zero,
+        Condition::AboveEqual => !carry
     }
 }
 
-fn fault(cause : String) {
-    if CONTINUE_AFTER_FAULT {
-        println!("!!! FAULTED !!!\n  Cause: {}", cause)
-    } else {
-        panic!("!!! FAULTED !!!\n  Cause: {}", cause);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::parse_asm;
+
+    fn machine(source : &'static str) -> Executor {
+        Executor::new(parse_asm(source).unwrap(), VFS::create_empty(), MachineConfig::default()).unwrap()
+    }
+
+    // Assembles `.vasm` source, for programs with labels.
+    fn assembled(source : &str, config : MachineConfig) -> Executor {
+        let image : &'static str = Box::leak(crate::assembler::assemble(source).unwrap().into_boxed_str());
+        Executor::new(parse_asm(image).unwrap(), VFS::create_empty(), config).unwrap()
+    }
+
+    const JUMPS : [&str; 12] = ["jz", "jnz", "je", "jne", "jl", "jle", "jg", "jge", "jb", "jbe", "ja", "jae"];
+
+    // Whether each of `JUMPS` is taken after `cmp lhs rhs`, once as a jump and once as a call
+    // returning through `esr`. `setup` runs first.
+    fn check_conditions(setup : &str, lhs : &str, rhs : &str, taken : [bool; 12]) {
+        for (jump, taken) in JUMPS.iter().zip(taken) {
+            let call = format!("call{}", &jump[1..]);
+            let jumped = format!("{}\ncmp {} {}\n{} yes\nhlt 0\nlabel yes\nhlt 1", setup, lhs, rhs, jump);
+            let called = format!("{}\ncmp {} {}\n{} f\nhlt rbx\nlabel f\nmov rbx 1\nesr", setup, lhs, rhs, call);
+            for (name, source) in [(jump.to_string(), jumped), (call, called)] {
+                let reason = assembled(&source, MachineConfig::default()).run().unwrap();
+                assert_eq!(reason, ExitReason::Halted(taken as u32), "{} after cmp {} {} ({})", name, lhs, rhs, setup);
+            }
+        }
+    }
+
+    fn check(op : ArithOp, bits : u32, cases : &[(u32, u32, u32, &str)]) {
+        for &(lhs, rhs, value, flags) in cases {
+            let (got, got_flags) = arith(op, lhs, rhs, bits).unwrap();
+            assert_eq!(
+                (got, format_flags(got_flags).as_str()),
+                (value, flags),
+                "{:?} {:#x} {:#x} at {} bits", op, lhs, rhs, bits
+            );
+        }
+    }
+
+    #[test]
+    fn add_flags() {
+        check(ArithOp::Add, 8, &[
+            (1, 2, 3, "----"),
+            (0xFF, 1, 0, "ZC--"),
+            (0x7F, 1, 0x80, "--SO"),
+            (0x80, 0x80, 0, "ZC-O"),
+            (0x1FF, 0, 0xFF, "--S-")
+        ]);
+        check(ArithOp::Add, 16, &[
+            (0xFFFF, 1, 0, "ZC--"),
+            (0x7FFF, 1, 0x8000, "--SO"),
+            (0x1_FFFF, 0, 0xFFFF, "--S-")
+        ]);
+        check(ArithOp::Add, 32, &[
+            (0xFFFF_FFFF, 1, 0, "ZC--"),
+            (0x7FFF_FFFF, 1, 0x8000_0000, "--SO"),
+            (0x8000_0000, 0x8000_0000, 0, "ZC-O")
+        ]);
+    }
+
+    #[test]
+    fn sub_flags() {
+        check(ArithOp::Sub, 8, &[
+            (5, 5, 0, "Z---"),
+            (0, 1, 0xFF, "-CS-"),
+            (0x80, 1, 0x7F, "---O"),
+            (0x7F, 0xFF, 0x80, "-CSO")
+        ]);
+        check(ArithOp::Sub, 16, &[
+            (0, 1, 0xFFFF, "-CS-"),
+            (0x8000, 1, 0x7FFF, "---O")
+        ]);
+        check(ArithOp::Sub, 32, &[
+            (0, 1, 0xFFFF_FFFF, "-CS-"),
+            (0x8000_0000, 1, 0x7FFF_FFFF, "---O")
+        ]);
+    }
+
+    #[test]
+    fn mul_flags() {
+        check(ArithOp::Mul, 8, &[
+            (3, 4, 12, "----"),
+            (16, 16, 0, "ZC-O"),
+            (0xFF, 0xFF, 1, "-C--"),
+            (0xFF, 2, 0xFE, "-CS-"),
+            (64, 2, 0x80, "--SO")
+        ]);
+        check(ArithOp::Mul, 16, &[
+            (0x100, 0x100, 0, "ZC-O"),
+            (0xFFFF, 0xFFFF, 1, "-C--")
+        ]);
+        check(ArithOp::Mul, 32, &[
+            (0x1_0000, 0x1_0000, 0, "ZC-O"),
+            (0xFFFF_FFFF, 0xFFFF_FFFF, 1, "-C--"),
+            (0x4000_0000, 2, 0x8000_0000, "--SO")
+        ]);
+    }
+
+    #[test]
+    fn div_and_bitwise_flags() {
+        assert_eq!(arith(ArithOp::Div, 7, 0, 32), None);
+        check(ArithOp::Div, 32, &[(7, 2, 3, "----"), (0, 5, 0, "Z---")]);
+        check(ArithOp::Div, 8, &[(0x1FF, 0x10, 0x0F, "----")]);
+        check(ArithOp::And, 8, &[(0xF0, 0x0F, 0, "Z---")]);
+        check(ArithOp::Or, 16, &[(0x8000, 1, 0x8001, "--S-")]);
+        check(ArithOp::Xor, 32, &[(0xFFFF_FFFF, 0xFFFF_FFFF, 0, "Z---")]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn shift_flags() {
+        check(ArithOp::Shl, 8, &[
+            (0xFF, 0, 0xFF, "--S-"),
+            (0x81, 1, 0x02, "-C--"),
+            (0x01, 8, 0, "ZC--"),
+            (0x01, 9, 0, "Z---"),
+            (0xFF, 200, 0, "Z---")
+        ]);
+        check(ArithOp::Shl, 16, &[(0x4000, 2, 0, "ZC--")]);
+        check(ArithOp::Shl, 32, &[
+            (0x8000_0000, 1, 0, "ZC--"),
+            (1, 32, 0, "ZC--"),
+            (1, 33, 0, "Z---")
+        ]);
+
+        check(ArithOp::Shr, 8, &[
+            (0x80, 0, 0x80, "--S-"),
+            (0x01, 1, 0, "ZC--"),
+            (0x80, 8, 0, "ZC--"),
+            (0x80, 9, 0, "Z---")
+        ]);
+        check(ArithOp::Shr, 32, &[(0x8000_0000, 31, 1, "----"), (1, 33, 0, "Z---")]);
+
+        check(ArithOp::Sar, 8, &[
+            (0x80, 0, 0x80, "--S-"),
+            (0x80, 1, 0xC0, "--S-"),
+            (0x40, 7, 0, "ZC--"),
+            (0x80, 8, 0xFF, "-CS-"),
+            (0x80, 200, 0xFF, "-CS-"),
+            (0x7F, 200, 0, "Z---")
+        ]);
+        check(ArithOp::Sar, 16, &[(0x8000, 15, 0xFFFF, "--S-")]);
+        check(ArithOp::Sar, 32, &[(0x8000_0000, 31, 0xFFFF_FFFF, "--S-"), (0x8000_0000, 32, 0xFFFF_FFFF, "-CS-")]);
+    }
+
+    #[test]
+    fn rotate_flags() {
+        check(ArithOp::Rol, 8, &[
+            (0x81, 0, 0x81, "--S-"),
+            (0x81, 1, 0x03, "-C--"),
+            (0x81, 8, 0x81, "-CS-"),
+            (0x81, 9, 0x03, "-C--"),
+            (0, 3, 0, "Z---")
+        ]);
+        check(ArithOp::Rol, 16, &[(0x8001, 4, 0x0018, "----"), (0x8001, 16, 0x8001, "-CS-")]);
+        check(ArithOp::Rol, 32, &[(0x8000_0000, 1, 1, "-C--"), (2, 32, 2, "----")]);
+
+        check(ArithOp::Ror, 8, &[
+            (0x81, 0, 0x81, "--S-"),
+            (0x01, 1, 0x80, "-CS-"),
+            (0x02, 8, 0x02, "----"),
+            (0x01, 9, 0x80, "-CS-")
+        ]);
+        check(ArithOp::Ror, 16, &[(0x0001, 16, 0x0001, "----")]);
+        check(ArithOp::Ror, 32, &[(1, 1, 0x8000_0000, "-CS-"), (0x8000_0000, 32, 0x8000_0000, "-CS-")]);
+    }
+
+    #[test]
+    fn signed_and_unsigned_conditions() {
+        //                                             z      nz     e      ne     l      le     g      ge     b      be     a      ae
+        check_conditions("mov rax 4294967295", "rax", "#1", [false, true,  false, true,  true,  true,  false, false, false, false, true,  true ]);
+        check_conditions("mov rax 1", "rax", "#1",          [true,  false, true,  false, false, true,  false, true,  false, true,  false, true ]);
+        check_conditions("mov rax 1", "rax", "#4294967295", [false, true,  false, true,  false, false, true,  true,  true,  true,  false, false]);
+        // Signed overflow: the S flag alone gives the wrong answer.
+        check_conditions("mov rax 2147483647", "rax", "#4294967295", [false, true, false, true, false, false, true, true, true, true, false, false]);
+        check_conditions("mov rax 2147483648", "rax", "#1", [false, true,  false, true,  true,  true,  false, false, false, false, true,  true ]);
+    }
+
+    #[test]
+    fn conditions_use_the_operand_width() {
+        //                                          z      nz     e      ne     l      le     g      ge     b      be     a      ae
+        check_conditions("mov rax 300", "al", "#44",  [true,  false, true,  false, false, true,  false, true,  false, true,  false, true ]);
+        check_conditions("mov rax 300", "al", "#200", [false, true,  false, true,  false, false, true,  true,  true,  true,  false, false]);
+        check_conditions("mov rax 300", "ax", "#44",  [false, true,  false, true,  false, false, true,  true,  false, false, true,  true ]);
+    }
+
+    fn words(mem_size : usize, reserved : usize) -> Executor {
+        let config = MachineConfig { mem_size, reserved, ..MachineConfig::default() };
+        Executor::new(parse_asm("/// END COMPILER GENERATED LABEL TABLE ///\n").unwrap(), VFS::create_empty(), config).unwrap()
+    }
+
+    fn word_error(result : Result<impl fmt::Debug, ExecError>) -> ExecErrorKind {
+        result.unwrap_err().kind
+    }
+
+    #[test]
+    fn words_are_little_endian() {
+        let mut exec = words(64, 16);
+        exec.store_word(20, 4, 0x0403_0201).unwrap();
+        assert_eq!(&exec.memory()[20..24], &[1, 2, 3, 4]);
+        assert_eq!(exec.load_word(20, 4).unwrap(), 0x0403_0201);
+        assert_eq!(exec.load_word(22, 2).unwrap(), 0x0403);
+
+        // Only the low bytes are stored.
+        exec.store_word(24, 2, 0x1_FFFF).unwrap();
+        assert_eq!(&exec.memory()[24..27], &[0xFF, 0xFF, 0]);
+
+        let mut exec = assembled("store32 [20] #305419896\nload16 rax [20]\nload32 rbx [20]", MachineConfig::default());
+        exec.run().unwrap();
+        assert_eq!(exec.register(Register::RAX), 0x5678);
+        assert_eq!(exec.register(Register::RBX), 0x1234_5678);
+    }
+
+    #[test]
+    fn words_must_be_aligned() {
+        let mut exec = words(64, 16);
+        assert!(matches!(word_error(exec.load_word(22, 4)), ExecErrorKind::Misaligned(22)));
+        assert!(matches!(word_error(exec.load_word(21, 2)), ExecErrorKind::Misaligned(21)));
+        assert!(matches!(word_error(exec.store_word(18, 4, 1)), ExecErrorKind::Misaligned(18)));
+        assert!(exec.load_word(18, 2).is_ok());
+        assert_eq!(&exec.memory()[16..24], &[0; 8]);
+    }
+
+    #[test]
+    fn words_must_be_in_bounds() {
+        let mut exec = words(64, 16);
+        exec.store_word(60, 4, u32::MAX).unwrap();
+        assert_eq!(exec.load_word(60, 4).unwrap(), u32::MAX);
+        assert_eq!(exec.load_word(62, 2).unwrap(), 0xFFFF);
+        assert!(matches!(word_error(exec.load_word(64, 4)), ExecErrorKind::Segfault(64)));
+        assert!(matches!(word_error(exec.load_word(64, 2)), ExecErrorKind::Segfault(64)));
+        assert!(matches!(word_error(exec.load_word(usize::MAX - 1, 2)), ExecErrorKind::Segfault(_)));
+
+        // Reserved memory starts the region off limits.
+        assert!(exec.load_word(16, 4).is_ok());
+        assert!(matches!(word_error(exec.load_word(12, 4)), ExecErrorKind::Segfault(12)));
+        assert!(matches!(word_error(exec.store_word(14, 2, 1)), ExecErrorKind::Segfault(14)));
+
+        // The whole word must fit, nothing is written when it doesn't.
+        let mut exec = words(62, 18);
+        assert!(matches!(word_error(exec.store_word(60, 4, u32::MAX)), ExecErrorKind::Segfault(60)));
+        assert_eq!(&exec.memory()[60..62], &[0, 0]);
+        exec.store_word(60, 2, u32::MAX).unwrap();
+        assert_eq!(&exec.memory()[60..62], &[0xFF, 0xFF]);
+        assert!(matches!(word_error(exec.load_word(16, 4)), ExecErrorKind::Segfault(16)));
+        assert!(exec.load_word(20, 4).is_ok());
+    }
+
+    #[test]
+    fn load_drops_return_addresses() {
+        let mut exec = machine("f 2\n/// END COMPILER GENERATED LABEL TABLE ///\ncall f hlt label f");
+        exec.step().unwrap();
+        assert_eq!(exec.call_depth(), 1);
+
+        exec.load(parse_asm("/// END COMPILER GENERATED LABEL TABLE ///\nesr").unwrap()).unwrap();
+        assert_eq!(exec.call_depth(), 0);
+        assert!(matches!(exec.run().unwrap_err().kind, ExecErrorKind::ReturnWithoutCall));
+    }
+
+    #[test]
+    fn load_clears_halt() {
+        let mut exec = machine("/// END COMPILER GENERATED LABEL TABLE ///\nhlt 7");
+        assert!(matches!(exec.run().unwrap(), ExitReason::Halted(7)));
+
+        exec.load(parse_asm("/// END COMPILER GENERATED LABEL TABLE ///\nmov rax 65 hlt 1").unwrap()).unwrap();
+        assert!(matches!(exec.run().unwrap(), ExitReason::Halted(1)));
+        assert_eq!(exec.register(Register::RAX), 65);
+    }
+
+    #[test]
+    fn stack_region_must_fit() {
+        let with_region = |base, size| {
+            let config = MachineConfig {
+                mem_size: 512,
+                reserved: 64,
+                stack_region: Some(StackRegion { base, size }),
+                ..MachineConfig::default()
+            };
+            Executor::new(parse_asm("/// END COMPILER GENERATED LABEL TABLE ///\npush #1").unwrap(), VFS::create_empty(), config)
+        };
+        assert!(with_region(496, 16).is_ok());
+        assert!(with_region(64, 16).is_ok());
+        assert!(with_region(600, 16).is_err());
+        assert!(with_region(500, 16).is_err());
+        assert!(with_region(0, 16).is_err());
+        assert!(with_region(usize::MAX, 16).is_err());
+        assert!(with_region(490, 20).is_err());
+        assert!(with_region(492, 18).is_err());
+        assert!(with_region(66, 16).is_err());
+
+        let config = MachineConfig {
+            mem_size: 1 << 33,
+            stack_region: Some(StackRegion { base: u32::MAX as usize - 10, size: 16 }),
+            ..MachineConfig::default()
+        };
+        assert!(check_stack_region(config.stack_region.unwrap(), &config).is_err());
+    }
+
+    #[test]
+    fn exit_codes_are_capped() {
+        assert_eq!(ExitReason::Finished.code(), 0);
+        assert_eq!(ExitReason::Halted(7).code(), 7);
+        assert_eq!(ExitReason::Halted(255).code(), 255);
+        assert_eq!(ExitReason::Halted(256).code(), 255);
+        assert_eq!(ExitReason::Halted(u32::MAX).code(), 255);
+    }
+
+    #[test]
+    fn interrupt_entries_are_range_checked() {
+        let config = MachineConfig { ivt: Some(0), ..MachineConfig::default() };
+        let source = "/// END COMPILER GENERATED LABEL TABLE ///\nmemset 5 200 int 1 hlt 3";
+        let mut exec = Executor::new(parse_asm(source).unwrap(), VFS::create_empty(), config).unwrap();
+        let e = exec.run().unwrap_err();
+        assert!(matches!(e.kind, ExecErrorKind::JumpOutOfRange(51199)));
+        assert_eq!(exec.index(), 1);
+    }
+
+    #[test]
+    fn faults_in_exception_handlers_are_skipped() {
+        let config = MachineConfig { ivt: Some(0), reserved: 128, continue_after_fault: true, ..MachineConfig::default() };
+        let mut exec = assembled(
+            "ivt 2 onseg\nmov rax [600]\noutstr #65\nhlt 3\nlabel onseg\nmov rbx [700]\noutstr #72\niret",
+            config
+        );
+        let out = crate::console::BufferOutput::new();
+        exec.set_output(Box::new(out.clone()));
+        assert_eq!(exec.run().unwrap(), ExitReason::Halted(3));
+        assert_eq!(
+            out.text(),
+            "!!! FAULTED !!!\n  Cause: Segmentation fault - Accessed memory out of bounds. Address: 700 at instr #4 (`mov rbx [700]`)\nHA"
+        );
+    }
+
+    #[test]
+    fn executor_is_send() {
+        fn assert_send<T : Send>() {}
+        assert_send::<Executor>();
+    }
+}
@@ -0,0 +1,29 @@
+//! A small virtual CPU that runs `.vraw`/`.vbin` program images.
+//!
+//! ```no_run
+//! use vcpu::{parse_asm, Executor, MachineConfig, VFS};
+//!
+//! let assembly = parse_asm("/// END COMPILER GENERATED LABEL TABLE ///\noutstr 65").unwrap();
+//! let mut exec = Executor::new(assembly, VFS::create_empty(), MachineConfig::default()).unwrap();
+//! while exec.step().unwrap().is_none() {}
+//! ```
+
+pub mod assembler;
+pub mod bytecode;
+pub mod console;
+pub mod debugger;
+pub mod decoder;
+pub mod disasm;
+pub mod exec;
+pub mod syscall;
+pub mod tokenizer;
+pub mod trace;
+pub mod vfs;
+
+pub use console::{BufferInput, BufferOutput, FileOutput, InputSource, OutputSink, StdinInput, StdoutOutput, TeeOutput};
+pub use decoder::{Condition, Instruction, MemRef, Operand, Program, Register};
+pub use exec::{DumpVerbosity, ExecError, ExecErrorKind, Executor, ExitReason, MachineConfig, StackRegion};
+pub use syscall::{SyscallError, SyscallHandler};
+pub use tokenizer::{parse_asm, load_image, Assembly};
+pub use trace::{TraceFormat, Tracer};
+pub use vfs::{File, VFS};
@@ -1,30 +1,307 @@
-#![allow(non_snake_case)]
+use std::env;
+use std::fs;
+use std::io::{self, BufWriter, ErrorKind, Write};
+use std::path::Path;
+use std::process;
 
-use exec::Executor;
-use vfs::VFS;
+use vcpu::{assembler, bytecode, tokenizer};
+use vcpu::debugger::Debugger;
+use vcpu::disasm;
+use vcpu::{BufferInput, DumpVerbosity, Executor, FileOutput, InputSource, MachineConfig, StackRegion, StdoutOutput, TeeOutput, TraceFormat, Tracer, VFS};
 
-mod exec;
-mod tokenizer;
-mod vfs;
+const USAGE : &str = "Usage:
+  vcpu run <boot image> [--file name=path ...] [--mem N] [--reserved N] [--stack-limit N] [--call-limit N] [--mem-stack base:size] [--registers N] [--ivt addr] [--continue-after-fault] [--dump registers|memory|full]
+  vcpu debug <boot image> [same options as run]
+  vcpu asm <input.vasm> [-o <output>] [--bin]
+  vcpu disasm <image>
 
-#[macro_use]
-extern crate lazy_static;
+Options for run and debug:
+  --trace <path|->           log every executed instruction, `-` for stderr
+  --trace-format text|json   trace as readable lines or line-delimited JSON
+  --input <path>             read console input from a file instead of stdin, which `debug` keeps for its commands
+  --output <path>            also copy console output to a file";
 
-lazy_static! {
-    #[derive(Debug)]
-    static ref ROM : &'static str = include_str!(r"..\BOOT.vraw");
+fn main() {
+    let args : Vec<String> = env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        Some("run") => run(&args[1..]),
+        Some("debug") => debug(&args[1..]),
+        Some("asm") => asm(&args[1..]),
+        Some("disasm") => disassemble(&args[1..]),
+        _ => usage()
+    }
 }
 
-fn main() {
+// Console input for `debug` without --input. Reading faults the program instead of competing with
+// the debugger for stdin.
+struct NoInput;
+
+impl InputSource for NoInput {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        Err(NoInput::error())
+    }
+
+    fn ready(&mut self) -> io::Result<bool> {
+        Err(NoInput::error())
+    }
+}
+
+impl NoInput {
+    fn error() -> io::Error {
+        io::Error::new(ErrorKind::Unsupported, "stdin is used for debugger commands, pass --input <path> for program input")
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+// vcpu run <boot image> [--file name=path ...] [machine options]
+fn run(args : &[String]) {
+    match boot(args, true).run() {
+        Ok(reason) => process::exit(reason.code()),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// vcpu debug <boot image> [--file name=path ...] [machine options]
+fn debug(args : &[String]) {
+    // The debugger reads its commands from stdin, so the program only gets input from --input.
+    let mut debugger = Debugger::new(boot(args, false));
+    if let Err(e) = debugger.repl(io::stdin().lock(), &mut io::stdout()) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+// Builds a machine from the boot image and VFS files given on the command line. Without
+// `stdin_input`, console input has to come from --input.
+fn boot(args : &[String], stdin_input : bool) -> Executor {
+    let (boot, rest) = match args.split_first() {
+        Some((boot, rest)) if !boot.starts_with("--") => (boot, rest),
+        _ => usage()
+    };
+
+    let mut files : Vec<(String, String)> = Vec::new();
+    let mut options : Vec<String> = Vec::new();
+    let mut trace : Option<String> = None;
+    let mut trace_format = TraceFormat::Text;
+    let mut input : Option<String> = None;
+    let mut output : Option<String> = None;
+
+    let mut iter = rest.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--file" {
+            let spec = iter.next().unwrap_or_else(|| usage());
+            match spec.split_once('=') {
+                Some((name, path)) => files.push((name.to_owned(), path.to_owned())),
+                None => {
+                    eprintln!("Expected name=path after --file, got `{}`", spec);
+                    process::exit(2);
+                }
+            }
+        } else if arg == "--trace" {
+            trace = Some(iter.next().unwrap_or_else(|| usage()).clone());
+        } else if arg == "--input" {
+            input = Some(iter.next().unwrap_or_else(|| usage()).clone());
+        } else if arg == "--output" {
+            output = Some(iter.next().unwrap_or_else(|| usage()).clone());
+        } else if arg == "--trace-format" {
+            trace_format = match iter.next().map(String::as_str) {
+                Some("text") => TraceFormat::Text,
+                Some("json") => TraceFormat::Json,
+                _ => usage()
+            };
+        } else {
+            options.push(arg.clone());
+        }
+    }
+
+    let config = parse_config(&options).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        usage();
+    });
+
+    let image = read_host_file(boot);
+    let name = Path::new(boot).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_else(|| boot.clone());
+
+    // The boot image is always file 1, --file entries follow in the order given.
     let mut vfs = VFS::create_empty();
-    let f = vfs.create_file(ROM.as_bytes().to_vec(), "BOOT.vraw".to_owned(), true);
-    vfs.write_file(f).expect("Failed to write bootloader into VFS.");
+    let f = vfs.create_file(image.clone(), name, true);
+    vfs.write_file(f).expect("Failed to write boot image into VFS.");
+
+    for (name, path) in files {
+        let file = vfs.create_file(read_host_file(&path), name, true);
+        vfs.write_file(file).expect("Failed to write file into VFS.");
+    }
+
+    let assembly = tokenizer::load_image(&image).unwrap_or_else(|e| {
+        eprintln!("{}: {}", boot, e);
+        process::exit(1);
+    });
+
+    let mut exec = Executor::new(assembly, vfs, config).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
+    match input {
+        Some(path) => exec.set_input(Box::new(BufferInput::new(read_host_file(&path)))),
+        None if !stdin_input => exec.set_input(Box::new(NoInput)),
+        None => {}
+    }
+
+    if let Some(path) = output {
+        let file = FileOutput::create(&path).unwrap_or_else(|e| {
+            eprintln!("Failed to create {}: {}", path, e);
+            process::exit(1);
+        });
+        exec.set_output(Box::new(TeeOutput::new(Box::new(StdoutOutput), Box::new(file))));
+    }
+
+    if let Some(path) = trace {
+        let out : Box<dyn Write + Send> = if path == "-" {
+            Box::new(io::stderr())
+        } else {
+            let file = fs::File::create(&path).unwrap_or_else(|e| {
+                eprintln!("Failed to create {}: {}", path, e);
+                process::exit(1);
+            });
+            Box::new(BufWriter::new(file))
+        };
+        exec.set_tracer(Some(Tracer::new(out, trace_format)));
+    }
+
+    exec
+}
+
+fn read_host_file(path : &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|e| {
+        eprintln!("Failed to read {}: {}", path, e);
+        process::exit(1);
+    })
+}
+
+fn parse_config(args : &[String]) -> Result<MachineConfig, String> {
+    let mut config = MachineConfig::default();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().ok_or_else(|| format!("Missing value after {}", arg));
+        match arg.as_str() {
+            "--mem" => config.mem_size = parse_number(arg, value()?)?,
+            "--reserved" => config.reserved = parse_number(arg, value()?)?,
+            "--stack-limit" => config.stack_limit = Some(parse_number(arg, value()?)?),
+            "--mem-stack" => {
+                let value = value()?;
+                let (base, size) = value.split_once(':').ok_or_else(|| format!("Expected base:size after {}, got `{}`", arg, value))?;
+                config.stack_region = Some(StackRegion {
+                    base: parse_number(arg, base)?,
+                    size: parse_number(arg, size)?
+                });
+            },
+            "--registers" => {
+                config.registers = parse_number(arg, value()?)?;
+                if !(4..=16).contains(&config.registers) {
+                    return Err(format!("{} must be between 4 and 16", arg));
+                }
+            },
+            "--ivt" => config.ivt = Some(parse_number(arg, value()?)?),
+            "--call-limit" => config.call_limit = Some(parse_number(arg, value()?)?),
+            "--continue-after-fault" => config.continue_after_fault = true,
+            "--dump" => config.dump = match value()?.as_str() {
+                "registers" => DumpVerbosity::Registers,
+                "memory" => DumpVerbosity::Memory,
+                "full" => DumpVerbosity::Full,
+                other => return Err(format!("Unknown dump verbosity `{}`", other))
+            },
+            _ => return Err(format!("Unknown option `{}`", arg))
+        }
+    }
+
+    Ok(config)
+}
+
+fn parse_number(flag : &str, value : &str) -> Result<usize, String> {
+    value.parse::<usize>().map_err(|_| format!("Invalid number `{}` after {}", value, flag))
+}
+
+// vcpu asm <input.vasm> [-o <output>] [--bin]
+fn asm(args : &[String]) {
+    let mut input : Option<&String> = None;
+    let mut output : Option<&String> = None;
+    let mut binary = false;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" => output = Some(iter.next().unwrap_or_else(|| asm_usage())),
+            "--bin" => binary = true,
+            _ if input.is_none() => input = Some(arg),
+            _ => asm_usage()
+        }
+    }
+
+    let input = input.unwrap_or_else(|| asm_usage());
+    let output = match output {
+        Some(path) => Path::new(path).to_path_buf(),
+        None => Path::new(input).with_extension(if binary { "vbin" } else { "vraw" })
+    };
+
+    let source = fs::read_to_string(input).unwrap_or_else(|e| {
+        eprintln!("Failed to read {}: {}", input, e);
+        process::exit(1);
+    });
+
+    let vraw = assembler::assemble(&source).unwrap_or_else(|e| {
+        eprintln!("{}: {}", input, e);
+        process::exit(1);
+    });
+
+    let image = if binary {
+        let vraw : &'static str = Box::leak(vraw.into_boxed_str());
+        let assembly = tokenizer::parse_asm(vraw).expect("Assembler produced an unreadable label table.");
+        bytecode::encode(&assembly).unwrap_or_else(|e| {
+            eprintln!("{}: {}", input, e);
+            process::exit(1);
+        })
+    } else {
+        vraw.into_bytes()
+    };
+
+    if let Err(e) = fs::write(&output, image) {
+        eprintln!("Failed to write {}: {}", output.display(), e);
+        process::exit(1);
+    }
+}
+
+fn asm_usage() -> ! {
+    eprintln!("Usage: vcpu asm <input.vasm> [-o <output>] [--bin]");
+    process::exit(2);
+}
+
+// vcpu disasm <image>
+fn disassemble(args : &[String]) {
+    let path = match args {
+        [path] => path,
+        _ => usage()
+    };
 
-    let kernel = vfs.create_file(include_str!(r"../kernel.vraw").as_bytes().to_vec(), "kernel.vraw".to_owned(), true);
-    vfs.write_file(kernel).expect("Failed to write kernel into VFS.");
+    let assembly = tokenizer::load_image(&read_host_file(path)).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    });
 
-    let assembly = tokenizer::parse_asm(&ROM);
+    let disassembly = disasm::disassemble(&assembly);
+    print!("{}", disassembly.text);
 
-    let mut exec = Executor::new(assembly, vfs);
-    exec.run();
+    if disassembly.problems > 0 {
+        eprintln!("{}: {} problem(s) flagged", path, disassembly.problems);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,65 @@
+use std::fmt;
+
+/// Host services reachable from guest code through `sys n`.
+///
+/// Arguments are passed in `rbx`, `rcx` and `rdx` and the returned value is written to `rax`.
+/// Pointer arguments are addresses into `memory`, which is the whole of guest memory including
+/// the reserved region. Errors fault the guest like any other. Handlers must be `Send` so the
+/// executor owning them can be moved to another thread.
+///
+/// ```
+/// use vcpu::{parse_asm, Executor, MachineConfig, Register, SyscallError, VFS};
+///
+/// let assembly = parse_asm("/// END COMPILER GENERATED LABEL TABLE ///\nmov rbx 40 mov rcx 2 sys 1").unwrap();
+/// let mut exec = Executor::new(assembly, VFS::create_empty(), MachineConfig::default()).unwrap();
+/// exec.set_syscall_handler(Some(Box::new(|number, args : [u32; 3], _memory : &mut [u8]| match number {
+///     1 => Ok(args[0] + args[1]),
+///     _ => Err(SyscallError::unknown(number))
+/// })));
+/// exec.run().unwrap();
+/// assert_eq!(exec.register(Register::RAX), 42);
+/// ```
+pub trait SyscallHandler : Send {
+    fn syscall(&mut self, number : u32, args : [u32; 3], memory : &mut [u8]) -> Result<u32, SyscallError>;
+}
+
+// Closures work as handlers, for embedders that only need a few calls.
+impl<F> SyscallHandler for F
+where
+    F : FnMut(u32, [u32; 3], &mut [u8]) -> Result<u32, SyscallError> + Send
+{
+    fn syscall(&mut self, number : u32, args : [u32; 3], memory : &mut [u8]) -> Result<u32, SyscallError> {
+        self(number, args, memory)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SyscallError {
+    code : SyscallErrorCode
+}
+
+impl SyscallError {
+    /// The handler doesn't provide this call.
+    pub fn unknown(number : u32) -> Self {
+        Self { code: SyscallErrorCode::Unknown(number) }
+    }
+    /// The call exists but couldn't be carried out.
+    pub fn failed(message : impl Into<String>) -> Self {
+        Self { code: SyscallErrorCode::Failed(message.into()) }
+    }
+}
+
+impl fmt::Display for SyscallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.code {
+            SyscallErrorCode::Unknown(number) => write!(f, "Syscall error: No syscall {}", number),
+            SyscallErrorCode::Failed(message) => write!(f, "Syscall error: {}", message)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum SyscallErrorCode {
+    Unknown(u32),
+    Failed(String)
+}
@@ -1,27 +1,40 @@
+use std::fmt;
 use std::collections::HashMap;
 
+use crate::assembler::LABEL_TABLE_END;
+use crate::bytecode;
+
 #[derive(Debug)]
 pub struct Assembly {
     pub label_table : HashMap<String, usize>,
     pub tokens : Vec<&'static str>
 }
 
-pub fn parse_asm(file : &'static str) -> Assembly {
+#[derive(Debug, Clone)]
+pub struct ParseError;
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to parse label table - corrupted binary?")
+    }
+}
+
+pub fn parse_asm(file : &'static str) -> Result<Assembly, ParseError> {
     let split = file
-        .split_once("/// END COMPILER GENERATED LABEL TABLE ///")
-        .expect("Failed to parse label table - corrupted binary?");
+        .split_once(LABEL_TABLE_END)
+        .ok_or(ParseError)?;
 
     let label_table_raw : Vec<(usize, &str)> = split.0.split_whitespace().enumerate().collect();
-    let label_table = parse_label_table(label_table_raw);
+    let label_table = parse_label_table(label_table_raw)?;
     let tokens = split.1.split_whitespace().collect::<Vec<&'static str>>();
 
-    Assembly {
+    Ok(Assembly {
         label_table,
         tokens
-    }
+    })
 }
 
-fn parse_label_table(table : Vec<(usize, &str)>) -> HashMap<String, usize> {
+fn parse_label_table(table : Vec<(usize, &str)>) -> Result<HashMap<String, usize>, ParseError> {
     let mut parsed : HashMap<String, usize> = HashMap::new();
     let mut key : &str = "";
 
@@ -29,10 +42,20 @@ fn parse_label_table(table : Vec<(usize, &str)>) -> HashMap<String, usize> {
         if index % 2 == 0 {
             key = token
         } else {
-            let usize = token.parse::<usize>().expect("Failed to parse label table - corrupted binary?");
+            let usize = token.parse::<usize>().map_err(|_| ParseError)?;
             parsed.insert(key.to_owned(), usize);
         }
     }
 
-    parsed
-}
\ No newline at end of file
+    Ok(parsed)
+}
+
+/// Parses a program image in either format, `.vbin` bytecode or `.vraw` text.
+pub fn load_image(contents : &[u8]) -> Result<Assembly, String> {
+    if bytecode::is_bytecode(contents) {
+        return bytecode::load(contents).map_err(|e| e.to_string());
+    }
+
+    let text : &'static str = Box::leak(String::from_utf8_lossy(contents).to_string().into_boxed_str());
+    parse_asm(text).map_err(|e| e.to_string())
+}
@@ -0,0 +1,109 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// One human-readable line per instruction.
+    Text,
+    /// One JSON object per line.
+    Json
+}
+
+/// What a single executed instruction did.
+#[derive(Debug, Clone)]
+pub struct TraceRecord {
+    /// Number of instructions executed before this one.
+    pub step : u64,
+    pub index : usize,
+    /// Nearest preceding label and the distance from it.
+    pub label : Option<(String, usize)>,
+    pub instruction : String,
+    /// Register name, old value, new value.
+    pub registers : Vec<(&'static str, u32, u32)>,
+    /// Address, old byte, new byte.
+    pub memory : Vec<(usize, u8, u8)>,
+    /// Cause of the error the instruction raised, if any.
+    pub error : Option<String>
+}
+
+pub struct Tracer {
+    out : Box<dyn Write + Send>,
+    format : TraceFormat
+}
+
+impl Tracer {
+    pub fn new(out : Box<dyn Write + Send>, format : TraceFormat) -> Self {
+        Self { out, format }
+    }
+
+    pub fn record(&mut self, record : &TraceRecord) -> io::Result<()> {
+        let line = match self.format {
+            TraceFormat::Text => text(record),
+            TraceFormat::Json => json(record)
+        };
+        writeln!(self.out, "{}", line)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// `#12 main+3: add rax rbx rcx ; rcx: 0 -> 5, [10]: 0 -> 65`, then `; error: ...` if it faulted
+fn text(record : &TraceRecord) -> String {
+    let mut line = format!("#{}", record.index);
+    match &record.label {
+        Some((name, 0)) => write!(line, " {}", name).unwrap(),
+        Some((name, offset)) => write!(line, " {}+{}", name, offset).unwrap(),
+        None => {}
+    }
+    write!(line, ": {}", record.instruction).unwrap();
+
+    let changes : Vec<String> = record.registers.iter()
+        .map(|(name, old, new)| format!("{}: {} -> {}", name, old, new))
+        .chain(record.memory.iter().map(|(addr, old, new)| format!("[{}]: {} -> {}", addr, old, new)))
+        .collect();
+    if !changes.is_empty() {
+        write!(line, " ; {}", changes.join(", ")).unwrap();
+    }
+    if let Some(error) = &record.error {
+        write!(line, " ; error: {}", error).unwrap();
+    }
+    line
+}
+
+fn json(record : &TraceRecord) -> String {
+    let mut line = format!("{{\"step\":{},\"index\":{}", record.step, record.index);
+    match &record.label {
+        Some((name, offset)) => write!(line, ",\"label\":{},\"offset\":{}", quote(name), offset).unwrap(),
+        None => line.push_str(",\"label\":null"),
+    }
+    write!(line, ",\"instruction\":{}", quote(&record.instruction)).unwrap();
+
+    let registers : Vec<String> = record.registers.iter()
+        .map(|(name, old, new)| format!("{{\"register\":\"{}\",\"old\":{},\"new\":{}}}", name, old, new))
+        .collect();
+    let memory : Vec<String> = record.memory.iter()
+        .map(|(addr, old, new)| format!("{{\"address\":{},\"old\":{},\"new\":{}}}", addr, old, new))
+        .collect();
+    write!(line, ",\"registers\":[{}],\"memory\":[{}]", registers.join(","), memory.join(",")).unwrap();
+    match &record.error {
+        Some(error) => write!(line, ",\"error\":{}}}", quote(error)).unwrap(),
+        None => line.push_str(",\"error\":null}")
+    }
+    line
+}
+
+fn quote(s : &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
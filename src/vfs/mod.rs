@@ -19,12 +19,14 @@ impl fmt::Display for VfsError {
 }
 
 #[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
 enum VfsErrorCode {
     ENOPERM,
     ENOFILE
 }
 
 // Nowhere near an ideal implementation, deal w it.
+#[allow(clippy::upper_case_acronyms)]
 pub struct VFS {
     files : HashMap<u8, File>,
     ct : u8
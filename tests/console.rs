@@ -0,0 +1,190 @@
+use std::io;
+
+use vcpu::assembler::assemble;
+use vcpu::exec::FLAG_CARRY;
+use vcpu::{parse_asm, BufferInput, BufferOutput, DumpVerbosity, Executor, ExitReason, InputSource, MachineConfig, Register, TeeOutput, VFS};
+
+fn machine(source : &str, input : &str) -> Executor {
+    configured(source, input, MachineConfig::default())
+}
+
+fn configured(source : &str, input : &str, config : MachineConfig) -> Executor {
+    let image : &'static str = Box::leak(assemble(source).unwrap().into_boxed_str());
+    let mut exec = Executor::new(parse_asm(image).unwrap(), VFS::create_empty(), config).unwrap();
+    exec.set_input(Box::new(BufferInput::new(input)));
+    exec
+}
+
+// Runs to the end and returns everything the program wrote.
+fn output(mut exec : Executor) -> String {
+    let out = BufferOutput::new();
+    exec.set_output(Box::new(out.clone()));
+    exec.run().unwrap();
+    out.text()
+}
+
+// Input that never arrives.
+struct Waiting;
+
+impl InputSource for Waiting {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        unreachable!("poll must not read")
+    }
+
+    fn ready(&mut self) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+#[test]
+fn inbyte_reads_bytes_then_end_of_input() {
+    let mut exec = machine("inbyte rax\ninbyte rbx\ninbyte rcx", "hi");
+    assert_eq!(exec.run().unwrap(), ExitReason::Finished);
+    assert_eq!(exec.register(Register::RAX), b'h' as u32);
+    assert_eq!(exec.register(Register::RBX), b'i' as u32);
+    assert_eq!(exec.register(Register::RCX), u32::MAX);
+}
+
+#[test]
+fn carry_marks_end_of_input_in_byte_destinations() {
+    let mut exec = machine("inbyte al\nrdf rbx\ninbyte [100]\nrdf rcx\ninbyte al\nrdf rdx", "");
+    exec.set_input(Box::new(BufferInput::new([0xFF])));
+    exec.run().unwrap();
+    assert_eq!(exec.register(Register::RAX), 0xFF);
+    assert_eq!(exec.register(Register::RBX) & FLAG_CARRY, 0);
+    assert_eq!(exec.memory()[100], 0xFF);
+    assert_eq!(exec.register(Register::RCX) & FLAG_CARRY, FLAG_CARRY);
+    assert_eq!(exec.register(Register::RDX) & FLAG_CARRY, FLAG_CARRY);
+
+    let mut exec = machine("readln [100] 16 al\nrdf rbx\nreadln [100] 16 al\nrdf rcx", "x");
+    exec.run().unwrap();
+    assert_eq!(exec.register(Register::RBX) & FLAG_CARRY, 0);
+    assert_eq!(exec.register(Register::RCX) & FLAG_CARRY, FLAG_CARRY);
+    assert_eq!(exec.register(Register::RAX), 0xFF);
+}
+
+#[test]
+fn echo_stops_on_carry_not_on_0xff() {
+    let source = "
+        label loop
+        inbyte [100]
+        jb done
+        outstr [100]
+        goto loop
+        label done
+    ";
+    let mut exec = machine(source, "");
+    exec.set_input(Box::new(BufferInput::new([b'a', 0xFF, b'b'])));
+    let out = BufferOutput::new();
+    exec.set_output(Box::new(out.clone()));
+    exec.run().unwrap();
+    assert_eq!(out.contents(), [b'a', 0xFF, b'b']);
+}
+
+#[test]
+fn poll_reports_whether_input_is_ready() {
+    let mut exec = machine("poll rax\ninbyte rbx\npoll rcx", "x");
+    exec.run().unwrap();
+    assert_eq!(exec.register(Register::RAX), 1);
+    assert_eq!(exec.register(Register::RBX), b'x' as u32);
+    // The end of input doesn't wait either.
+    assert_eq!(exec.register(Register::RCX), 1);
+
+    let mut exec = machine("mov rax 5\npoll rax", "");
+    exec.set_input(Box::new(Waiting));
+    exec.run().unwrap();
+    assert_eq!(exec.register(Register::RAX), 0);
+}
+
+#[test]
+fn readln_splits_lines_and_respects_max() {
+    let mut exec = machine(
+        "readln [100] 16 rax\nreadln [200] 3 rbx\nreadln [300] 16 rcx\nreadln [400] 16 rdx",
+        "hello\nworld"
+    );
+    exec.run().unwrap();
+    assert_eq!(exec.register(Register::RAX), 5);
+    assert_eq!(&exec.memory()[100..106], b"hello\0");
+    assert_eq!(exec.register(Register::RBX), 3);
+    assert_eq!(&exec.memory()[200..204], b"wor\0");
+    assert_eq!(exec.register(Register::RCX), 2);
+    assert_eq!(&exec.memory()[300..303], b"ld\0");
+    assert_eq!(exec.register(Register::RDX), u32::MAX);
+}
+
+#[test]
+fn readln_reads_empty_lines() {
+    let mut exec = machine("readln [100] 16 rax\nreadln [100] 16 rbx", "\nx");
+    exec.run().unwrap();
+    assert_eq!(exec.register(Register::RAX), 0);
+    assert_eq!(exec.register(Register::RBX), 1);
+    assert_eq!(exec.memory()[100], b'x');
+}
+
+#[test]
+fn outstr_and_outbyte_write_to_the_sink() {
+    let out = output(machine("outstr #72\noutstr 105\noutbyte #42\nmov rax 7\noutbyte rax\nmov [100] 65\noutstr [100]", ""));
+    assert_eq!(out, "Hi427A");
+}
+
+#[test]
+fn echo_matches_its_input() {
+    let source = "
+        label loop
+        inbyte rax
+        cmp rax #4294967295
+        je done
+        outstr rax
+        goto loop
+        label done
+    ";
+    let input = "first line\nsecond line\n";
+    assert_eq!(output(machine(source, input)), input);
+}
+
+#[test]
+fn readln_echoes_lines_through_outstr() {
+    let source = "
+        label line
+        readln [100] 64 rcx
+        cmp rcx #4294967295
+        je done
+        mov rbx 0
+        label char
+        cmp rbx rcx
+        je end_of_line
+        outstr [rbx+100]
+        add rbx 1 rbx
+        goto char
+        label end_of_line
+        outstr #10
+        goto line
+        label done
+    ";
+    assert_eq!(output(machine(source, "one\ntwo\n\nthree")), "one\ntwo\n\nthree\n");
+}
+
+#[test]
+fn dmp_writes_to_the_sink() {
+    let config = MachineConfig { dump: DumpVerbosity::Registers, ..MachineConfig::default() };
+    let out = output(configured("mov rax 7\ndmp\noutstr #33", "", config));
+    assert!(out.starts_with("!!! DUMPED !!!\n  Registers:\n    RAX: 7\n"), "{}", out);
+    assert!(out.ends_with("    FLAGS: ----\n!"), "{}", out);
+}
+
+#[test]
+fn skipped_faults_are_reported_through_the_sink() {
+    let config = MachineConfig { continue_after_fault: true, ..MachineConfig::default() };
+    let out = output(configured("outstr #65\nfault\noutstr #66", "", config));
+    assert_eq!(out, "A!!! FAULTED !!!\n  Cause: Fault requested at instr #1 (`fault`)\nB");
+}
+
+#[test]
+fn tee_copies_output_to_both_sinks() {
+    let (first, second) = (BufferOutput::new(), BufferOutput::new());
+    let mut exec = machine("outstr #111\noutstr #107", "");
+    exec.set_output(Box::new(TeeOutput::new(Box::new(first.clone()), Box::new(second.clone()))));
+    exec.run().unwrap();
+    assert_eq!(first.contents(), b"ok");
+    assert_eq!(second.contents(), b"ok");
+}